@@ -1,111 +1,630 @@
-use core::{future::Future, net::SocketAddr};
+use core::{
+    fmt, mem,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
-use std::{io, net::ToSocketAddrs, sync::Arc};
+use std::{
+    io,
+    net::ToSocketAddrs,
+    sync::{Arc, RwLock},
+};
 
-use tokio::net::UdpSocket;
-use tracing::error;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    task::JoinSet,
+    time::timeout,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, warn};
 
 use crate::{
     cache::Cache,
-    config::{Config, UpstreamVariant},
-    error::Error,
-    proxy::{udp::UdpProxy, ProxyDyn},
+    config::{self, Config, Route, Upstream, UpstreamGroup, UpstreamVariant},
+    dns::{self, ResultCode},
+    error::{Error, TimeoutError},
+    proxy::{
+        group::{self, GroupProxy},
+        route::RouteProxy,
+        tcp::TcpProxy,
+        udp::UdpProxy,
+        Proxy, ProxyDyn,
+    },
+    util::{self, LogHandle},
 };
 
+#[cfg(any(feature = "tls", feature = "https", feature = "quic"))]
+mod cert;
+#[cfg(feature = "https")]
+mod https;
+#[cfg(feature = "quic")]
+mod quic;
+mod signal;
+mod tcp;
+#[cfg(any(feature = "tls", feature = "https"))]
+mod tls;
+
+// max size of dns message. RFC 1035 4.2.2
+const MAX_MSG_LEN: usize = u16::MAX as usize;
+
+// max duration for in-flight queries to be answered and upstream connections to be closed on
+// shutdown.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct App {
-    listener: UdpSocket,
+    listeners: Vec<Listener>,
+    #[cfg(feature = "tls")]
+    tls_listener: Option<tls::TlsListener>,
+    #[cfg(feature = "https")]
+    https_listener: Option<tls::TlsListener>,
+    #[cfg(feature = "https")]
+    http_listener: Option<https::HttpListener>,
+    #[cfg(feature = "quic")]
+    quic_listener: Option<quic::QuicListener>,
     cache: Cache,
-    proxy: Box<dyn ProxyDyn>,
+    // replaced on configuration reload.
+    policy: RwLock<Arc<Policy>>,
+    log: LogHandle,
+    // cancelled on shutdown. connections stop reading new queries when it happens.
+    shutdown: CancellationToken,
+    // tracker of spawned connections and queries.
+    tasks: TaskTracker,
 }
 
 impl App {
-    pub async fn run(cfg: Config) -> Result<(), Error> {
-        let app = App::try_from_config(cfg).await?;
+    pub async fn run(cfg: Config, log: LogHandle) -> Result<(), Error> {
+        let app = App::try_from_config(cfg, log).await?;
+
+        let mut set = JoinSet::new();
+
+        for idx in 0..app.listeners.len() {
+            set.spawn(app.clone().run_udp(idx));
+            set.spawn(app.clone().run_tcp(idx));
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(listener) = app.tls_listener.as_ref() {
+            for idx in 0..listener.len() {
+                set.spawn(app.clone().run_tls(idx));
+            }
+        }
+
+        #[cfg(feature = "https")]
+        if let Some(listener) = app.https_listener.as_ref() {
+            for idx in 0..listener.len() {
+                set.spawn(app.clone().run_https(idx));
+            }
+        }
+
+        #[cfg(feature = "https")]
+        if let Some(listener) = app.http_listener.as_ref() {
+            for idx in 0..listener.len() {
+                set.spawn(app.clone().run_http(idx));
+            }
+        }
+
+        #[cfg(feature = "quic")]
+        if let Some(listener) = app.quic_listener.as_ref() {
+            for idx in 0..listener.len() {
+                set.spawn(app.clone().run_quic(idx));
+            }
+        }
+
+        signal::shutdown(&mut set)?;
+        signal::reload(&mut set, app.clone())?;
+
+        // listeners only exit on fatal error. signal tasks exit on shutdown request.
+        if let Some(res) = set.join_next().await {
+            res??;
+        }
+
+        // stop accepting new connections and queries.
+        set.shutdown().await;
+
+        app.drain().await;
+
+        Ok(())
+    }
+
+    // wait for in-flight queries to be answered and close upstream connections after.
+    async fn drain(self: Arc<Self>) {
+        info!("shutting down. waiting for in-flight queries");
+
+        self.shutdown.cancel();
+        self.tasks.close();
+
+        if timeout(DRAIN_TIMEOUT, self.tasks.wait()).await.is_err() {
+            warn!("in-flight queries are not finished in {DRAIN_TIMEOUT:?}");
+        }
+
+        #[cfg(feature = "quic")]
+        if let Some(listener) = self.quic_listener.as_ref() {
+            let _ = timeout(DRAIN_TIMEOUT, listener.close()).await;
+        }
+
+        let policy = Arc::try_unwrap(self)
+            .ok()
+            .and_then(|this| Arc::try_unwrap(this.policy.into_inner().unwrap()).ok());
+
+        match policy {
+            Some(policy) => {
+                if timeout(DRAIN_TIMEOUT, policy.proxy.close_dyn())
+                    .await
+                    .is_err()
+                {
+                    warn!("upstream connections are not closed in {DRAIN_TIMEOUT:?}");
+                }
+            }
+            None => warn!("upstream connections are not closed. queries are still in-flight"),
+        }
+    }
+
+    // read configuration again and replace upstream proxy, policy and log level. listeners and
+    // cache are kept and changes to listener configuration are ignored.
+    // new configuration is rejected and old one is kept when it fails to be parsed or no upstream
+    // can be constructed from it.
+    pub(super) async fn reload(&self) -> Result<(), Error> {
+        let cfg = tokio::task::spawn_blocking(config::reparse_arg).await??;
+
+        info!("reloading configuration: {cfg:?}");
+
+        let policy = Policy::try_from_config(
+            cfg.upstream,
+            cfg.groups,
+            cfg.routes,
+            cfg.boot_strap_addr,
+            cfg.timeout,
+        )
+        .await?;
+
+        self.log.reload(util::log_filter(cfg.log_level))?;
 
-        let mut buf = [0; 512];
+        // old proxy is dropped when queries using it are finished.
+        *self.policy.write().unwrap() = Arc::new(policy);
+
+        info!("configuration reloaded");
+
+        Ok(())
+    }
+
+    fn policy(&self) -> Arc<Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    async fn run_udp(self: Arc<Self>, idx: usize) -> Result<(), Error> {
+        let mut buf = vec![0; MAX_MSG_LEN];
 
         loop {
-            match app.listener.recv_from(&mut buf).await {
-                Ok((len, addr)) => app.forward(&mut buf[..len], addr),
+            match self.listeners[idx].udp.recv_from(&mut buf).await {
+                Ok((len, addr)) => self.forward(&mut buf[..len], addr, idx),
                 Err(ref e) if connection_error(e) => continue,
                 Err(e) => return Err(e.into()),
             }
         }
     }
 
-    async fn try_from_config(cfg: Config) -> Result<Arc<Self>, Error> {
-        let listener = try_iter(cfg.listen_addr.into_iter(), UdpSocket::bind).await?;
+    async fn try_from_config(cfg: Config, log: LogHandle) -> Result<Arc<Self>, Error> {
+        let listeners = cfg
+            .listen_addr
+            .into_iter()
+            .map(Listener::bind)
+            .collect::<io::Result<Vec<_>>>()?;
 
-        let mut boot_strap = cfg.boot_strap_addr;
-        let _boot_strap = boot_strap.pop().unwrap().to_socket_addrs()?.next().unwrap();
-        let proxy = try_iter(cfg.upstream_addr.into_iter(), |addr| async move {
-            match addr {
-                UpstreamVariant::Udp(addr) => UdpProxy::try_from_addr(addr)
-                    .await
-                    .map(|p| Box::new(p) as _),
-                #[cfg(feature = "tls")]
-                UpstreamVariant::Tls(uri) => {
-                    crate::proxy::tls::TlsProxy::try_from_uri(uri, _boot_strap)
-                        .await
-                        .map(|p| Box::new(p) as _)
-                }
-                #[cfg(feature = "https")]
-                UpstreamVariant::Https(uri) => {
-                    crate::proxy::https::HttpProxy::try_from_uri(uri, _boot_strap)
-                        .await
-                        .map(|p| Box::new(p) as _)
-                }
+        #[cfg(feature = "tls")]
+        let tls_listener = match cfg.tls_listen_addr.is_empty() {
+            true => None,
+            false => {
+                let tls_cfg = cert::server_config(cfg.cert.as_deref(), cfg.key.as_deref(), &[])?;
+                Some(tls::TlsListener::bind(cfg.tls_listen_addr, tls_cfg)?)
             }
-        })
+        };
+
+        #[cfg(feature = "https")]
+        let https_listener = match cfg.https_listen_addr.is_empty() {
+            true => None,
+            false => {
+                let alpn: &[&[u8]] = &[b"h2", b"http/1.1"];
+                let tls_cfg = cert::server_config(cfg.cert.as_deref(), cfg.key.as_deref(), alpn)?;
+                Some(tls::TlsListener::bind(cfg.https_listen_addr, tls_cfg)?)
+            }
+        };
+
+        #[cfg(feature = "https")]
+        let http_listener = match cfg.http_listen_addr {
+            Some(addr) => Some(https::HttpListener::bind(addr)?),
+            None => None,
+        };
+
+        #[cfg(feature = "quic")]
+        let quic_listener = match cfg.quic_listen_addr.is_empty() {
+            true => None,
+            false => {
+                let tls_cfg =
+                    cert::server_config(cfg.cert.as_deref(), cfg.key.as_deref(), &[b"doq"])?;
+                Some(quic::QuicListener::bind(cfg.quic_listen_addr, tls_cfg)?)
+            }
+        };
+
+        let policy = Policy::try_from_config(
+            cfg.upstream,
+            cfg.groups,
+            cfg.routes,
+            cfg.boot_strap_addr,
+            cfg.timeout,
+        )
         .await?;
 
         Ok(Arc::new(Self {
-            listener,
+            listeners,
+            #[cfg(feature = "tls")]
+            tls_listener,
+            #[cfg(feature = "https")]
+            https_listener,
+            #[cfg(feature = "https")]
+            http_listener,
+            #[cfg(feature = "quic")]
+            quic_listener,
             cache: Cache::new(),
-            proxy,
+            policy: RwLock::new(Arc::new(policy)),
+            log,
+            shutdown: CancellationToken::new(),
+            tasks: TaskTracker::new(),
         }))
     }
 
-    fn forward(self: &Arc<Self>, buf: &mut [u8], addr: SocketAddr) {
-        let either = match self.cache.get(buf) {
-            Some(cache) => EitherBuf::Cache(cache),
-            None => EitherBuf::Req((&*buf).into()),
+    // validate configuration the same way try_from_config does without binding listeners or
+    // connecting to upstreams. every problem is collected into the error.
+    pub fn check(cfg: &Config) -> Result<(), Error> {
+        let mut errors = Vec::new();
+
+        #[cfg(feature = "tls")]
+        if !cfg.tls_listen_addr.is_empty() {
+            if let Err(e) = cert::server_config(cfg.cert.as_deref(), cfg.key.as_deref(), &[]) {
+                errors.push(format!("tls listener: {e}"));
+            }
+        }
+
+        #[cfg(feature = "https")]
+        if !cfg.https_listen_addr.is_empty() {
+            let alpn: &[&[u8]] = &[b"h2", b"http/1.1"];
+            if let Err(e) = cert::server_config(cfg.cert.as_deref(), cfg.key.as_deref(), alpn) {
+                errors.push(format!("https listener: {e}"));
+            }
+        }
+
+        #[cfg(feature = "quic")]
+        if !cfg.quic_listen_addr.is_empty() {
+            if let Err(e) = cert::server_config(cfg.cert.as_deref(), cfg.key.as_deref(), &[b"doq"])
+            {
+                errors.push(format!("quic listener: {e}"));
+            }
+        }
+
+        for Upstream { addr, .. } in cfg.upstream.addr.iter() {
+            if let Err(e) = Policy::check_upstream(addr) {
+                errors.push(format!("upstream {addr}: {e}"));
+            }
+        }
+
+        for (name, group) in cfg.groups.iter() {
+            for Upstream { addr, .. } in group.addr.iter() {
+                if let Err(e) = Policy::check_upstream(addr) {
+                    errors.push(format!("group {name}: upstream {addr}: {e}"));
+                }
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(Error::from(errors.join("\n"))),
+        }
+    }
+
+    // response is sent from the same socket the query is received on.
+    fn forward(self: &Arc<Self>, buf: &mut [u8], addr: SocketAddr, idx: usize) {
+        let edns = dns::udp_payload_size(buf);
+        let limit = edns.unwrap_or(512) as usize;
+        let Some(either) = self.lookup(buf, limit) else {
+            return;
         };
 
         let this = self.clone();
-        tokio::spawn(async move {
-            if let Err(e) = this._forward(either, addr).await {
+        self.tasks.spawn(async move {
+            if let Err(e) = this
+                ._forward(either, addr, idx, limit, edns.is_some())
+                .await
+            {
                 error!("forwarding dns lookup error: {e}")
             }
         });
     }
 
-    async fn _forward(&self, either: EitherBuf, addr: SocketAddr) -> Result<(), Error> {
-        let buf = match either {
-            EitherBuf::Cache(cache) => cache,
-            EitherBuf::Req(buf) => {
-                let mut res = self.proxy.proxy_dyn(buf).await?;
-                self.cache.set(&mut res);
-                res
-            }
-        };
-        self.listener.send_to(&buf, addr).await?;
+    async fn _forward(
+        &self,
+        either: EitherBuf,
+        addr: SocketAddr,
+        idx: usize,
+        limit: usize,
+        edns: bool,
+    ) -> Result<(), Error> {
+        let mut buf = self.resolve(either, Some(addr.ip())).await;
+        dns::truncate(&mut buf, limit, edns);
+        self.listeners[idx].udp.send_to(&buf, addr).await?;
         Ok(())
     }
+
+    // look up cache for dns query. on cache miss the query is copied for proxy.
+    // limit is the max size of response the client can receive. None is returned when the
+    // message is not a query and must be dropped.
+    fn lookup(&self, buf: &mut [u8], limit: usize) -> Option<EitherBuf> {
+        if !dns::is_query(buf) {
+            return None;
+        }
+
+        if let Err(code) = dns::check_query(buf) {
+            return Some(EitherBuf::Res(dns::error_response(buf, code)));
+        }
+
+        let either = match self.cache.get(buf, limit) {
+            Some(cache) => EitherBuf::Res(cache),
+            None => EitherBuf::Req((&*buf).into()),
+        };
+
+        Some(either)
+    }
+
+    // resolve query with upstream. SERVFAIL is responded when upstream fails or doesn't
+    // respond before deadline.
+    // client is the address query is from. it's absent when DoH request comes from unix socket
+    // without forwarded header.
+    async fn resolve(&self, either: EitherBuf, client: Option<IpAddr>) -> Vec<u8> {
+        match either {
+            EitherBuf::Res(res) => res,
+            EitherBuf::Req(mut buf) => {
+                let policy = self.policy();
+                let res = timeout(policy.timeout, policy.proxy.proxy_dyn(buf.clone()))
+                    .await
+                    .unwrap_or_else(|_| Err(Error::from(TimeoutError(policy.timeout))));
+                match res {
+                    Ok(mut res) => {
+                        self.cache.set(&mut res);
+                        return res;
+                    }
+                    Err(e) if e.is::<TimeoutError>() => {
+                        error!("forwarding dns lookup from {} {e}", Client(client))
+                    }
+                    Err(e) => error!("forwarding dns lookup from {} error: {e}", Client(client)),
+                }
+                dns::error_response(&mut buf, ResultCode::SERVFAIL)
+            }
+        }
+    }
+}
+
+// upstream proxy and settings of query forwarding. swapped as a whole on configuration reload.
+struct Policy {
+    proxy: Box<dyn ProxyDyn>,
+    timeout: Duration,
+}
+
+impl Policy {
+    // groups not referenced by any route are not constructed.
+    async fn try_from_config(
+        upstream: UpstreamGroup,
+        groups: Vec<(String, UpstreamGroup)>,
+        routes: Vec<Route>,
+        boot_strap_addr: Vec<SocketAddr>,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let mut boot_strap = boot_strap_addr;
+        let boot_strap = boot_strap.pop().unwrap().to_socket_addrs()?.next().unwrap();
+
+        let default = Self::try_from_group(upstream, boot_strap, timeout).await?;
+
+        if routes.is_empty() {
+            let proxy = Box::new(default);
+            return Ok(Self { proxy, timeout });
+        }
+
+        let mut proxies = vec![(config::DEFAULT_GROUP.to_string(), default)];
+        for (name, group) in groups {
+            if !routes.iter().any(|route| route.group == name) {
+                continue;
+            }
+            match Self::try_from_group(group, boot_strap, timeout).await {
+                Ok(proxy) => proxies.push((name, proxy)),
+                Err(e) => {
+                    for (_, proxy) in proxies {
+                        proxy.close().await;
+                    }
+                    return Err(format!("group {name}: {e}").into());
+                }
+            }
+        }
+
+        let proxy = Box::new(RouteProxy::new(proxies, routes));
+
+        Ok(Self { proxy, timeout })
+    }
+
+    // upstreams failed to be constructed are skipped. error is returned when none of them is
+    // available. deadline of query is split between attempts of upstreams without timeout.
+    async fn try_from_group(
+        mut upstream: UpstreamGroup,
+        boot_strap: SocketAddr,
+        timeout: Duration,
+    ) -> Result<GroupProxy, Error> {
+        let mut upstreams = Vec::new();
+        let mut err = None;
+
+        // timeout and retries of upstream take precedence over the ones of group.
+        for Upstream {
+            addr,
+            weight,
+            timeout,
+            retries,
+        } in mem::take(&mut upstream.addr)
+        {
+            let name = addr.to_string();
+            match Self::try_from_upstream(addr, boot_strap, &upstream).await {
+                Ok(proxy) => upstreams.push(group::Upstream::new(
+                    name,
+                    proxy,
+                    weight,
+                    timeout.or(upstream.timeout),
+                    retries.unwrap_or(upstream.retries),
+                )),
+                Err(e) => {
+                    warn!("upstream {name} is not available: {e}");
+                    err = Some(e);
+                }
+            }
+        }
+
+        if upstreams.is_empty() {
+            return Err(err.unwrap());
+        }
+
+        Ok(GroupProxy::new(
+            upstreams,
+            upstream.strategy,
+            upstream.hedge,
+            upstream.fallback,
+            timeout,
+        ))
+    }
+
+    // group provides settings of upstream proxy.
+    async fn try_from_upstream(
+        addr: UpstreamVariant,
+        _boot_strap: SocketAddr,
+        _group: &UpstreamGroup,
+    ) -> Result<Box<dyn ProxyDyn>, Error> {
+        match addr {
+            UpstreamVariant::Udp(addr) => UdpProxy::try_from_addr(addr)
+                .await
+                .map(|p| Box::new(p) as _),
+            UpstreamVariant::Tcp(addr) => Ok(Box::new(TcpProxy::new(addr))),
+            #[cfg(feature = "tls")]
+            UpstreamVariant::Tls(uri) => {
+                crate::proxy::tls::TlsProxy::try_from_uri(uri, _boot_strap, _group.tls_pool)
+                    .await
+                    .map(|p| Box::new(p) as _)
+            }
+            #[cfg(feature = "https")]
+            UpstreamVariant::Https(uri) => {
+                crate::proxy::https::HttpProxy::try_from_uri(uri, _boot_strap)
+                    .await
+                    .map(|p| Box::new(p) as _)
+            }
+            #[cfg(feature = "quic")]
+            UpstreamVariant::Quic(uri) => {
+                crate::proxy::quic::QuicProxy::try_from_uri(uri, _boot_strap)
+                    .await
+                    .map(|p| Box::new(p) as _)
+            }
+        }
+    }
+
+    fn check_upstream(addr: &UpstreamVariant) -> Result<(), Error> {
+        match addr {
+            UpstreamVariant::Udp(_) | UpstreamVariant::Tcp(_) => Ok(()),
+            #[cfg(feature = "tls")]
+            UpstreamVariant::Tls(uri) => crate::proxy::tls::TlsProxy::check_uri(uri).map(|_| ()),
+            #[cfg(feature = "https")]
+            UpstreamVariant::Https(uri) => crate::proxy::https::HttpProxy::check_uri(uri),
+            #[cfg(feature = "quic")]
+            UpstreamVariant::Quic(uri) => crate::proxy::quic::QuicProxy::check_uri(uri),
+        }
+    }
+}
+
+// udp socket and tcp listener bound to the same local address. RFC 7766
+struct Listener {
+    udp: UdpSocket,
+    tcp: TcpListener,
+}
+
+impl Listener {
+    fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let udp = socket(addr, Type::DGRAM, Protocol::UDP)?;
+        udp.bind(&addr.into())?;
+        let udp = UdpSocket::from_std(udp.into())?;
+
+        // use the actual address so tcp listener follows the port picked for udp socket.
+        let addr = udp.local_addr()?;
+
+        let tcp = tcp_listener(addr)?;
+
+        info!("listening on {addr}");
+
+        Ok(Self { udp, tcp })
+    }
+}
+
+fn tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let tcp = socket(addr, Type::STREAM, Protocol::TCP)?;
+    tcp.set_reuse_address(true)?;
+    tcp.bind(&addr.into())?;
+    tcp.listen(1024)?;
+    TcpListener::from_std(tcp.into())
+}
+
+// bind every resolved address of a listener. duplicate addresses are skipped as binding them twice
+// would fail.
+#[cfg(any(feature = "tls", feature = "https", feature = "quic"))]
+fn bind_all<F, T>(mut addr: Vec<SocketAddr>, func: F) -> io::Result<Vec<T>>
+where
+    F: Fn(SocketAddr) -> io::Result<T>,
+{
+    addr.sort();
+    addr.dedup();
+    addr.into_iter()
+        .map(|addr| {
+            let res = func(addr)?;
+            info!("listening on {addr}");
+            Ok(res)
+        })
+        .collect()
+}
+
+// ipv6 socket is set to v6 only so it can co-exist with ipv4 socket on the same port.
+fn socket(addr: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+// display of client address in log.
+struct Client(Option<IpAddr>);
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(addr) => addr.fmt(f),
+            None => f.write_str("unknown client"),
+        }
+    }
 }
 
 enum EitherBuf {
-    Cache(Vec<u8>),
+    // response ready to be sent. from cache or rejection of query.
+    Res(Vec<u8>),
     Req(Box<[u8]>),
 }
 
+#[cfg(any(feature = "tls", feature = "quic"))]
 #[cold]
 #[inline(never)]
 pub(crate) async fn try_iter<I, F, Fut, T, E>(addr: I, func: F) -> Result<T, E>
 where
     I: Iterator,
     F: Fn(I::Item) -> Fut,
-    Fut: Future<Output = Result<T, E>>,
+    Fut: core::future::Future<Output = Result<T, E>>,
 {
     let mut err = None;
 
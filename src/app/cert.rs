@@ -0,0 +1,45 @@
+use core::fmt;
+
+use std::{error, fs::File, io::BufReader, path::Path, sync::Arc};
+
+use rustls::ServerConfig;
+
+use crate::error::Error;
+
+// load certificate chain and private key from PEM files.
+pub(super) fn server_config(
+    cert: Option<&Path>,
+    key: Option<&Path>,
+    alpn: &[&[u8]],
+) -> Result<Arc<ServerConfig>, Error> {
+    let (Some(cert), Some(key)) = (cert, key) else {
+        return Err(Error::from(MissingCert));
+    };
+
+    let certs =
+        rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?)).collect::<Result<_, _>>()?;
+
+    let key = match rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))? {
+        Some(key) => key,
+        None => return Err(Error::from(MissingCert)),
+    };
+
+    let mut cfg = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    cfg.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+    Ok(Arc::new(cfg))
+}
+
+#[derive(Debug)]
+struct MissingCert;
+
+impl fmt::Display for MissingCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("tls listener requires a PEM encoded certificate chain and private key.")
+    }
+}
+
+impl error::Error for MissingCert {}
@@ -0,0 +1,272 @@
+use core::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    pin::pin,
+};
+
+use std::{io, sync::Arc};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{HeaderMap, HeaderValue, CACHE_CONTROL, CONTENT_TYPE, FORWARDED},
+    service::service_fn,
+    Method, Request, Response, StatusCode, Uri,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tracing::trace;
+
+use crate::{
+    config::ListenAddr,
+    dns::{Answer, Buf, Packet},
+    error::Error,
+    proxy::https::DNS_MSG_HDR,
+};
+
+use super::{bind_all, connection_error, tcp_listener, App, MAX_MSG_LEN};
+
+const PATH: &str = "/dns-query";
+
+// listener for cleartext DoH behind reverse proxy.
+pub(super) enum HttpListener {
+    // one listener per resolved address.
+    Tcp(Vec<TcpListener>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl HttpListener {
+    pub(super) fn bind(addr: ListenAddr) -> Result<Self, Error> {
+        match addr {
+            ListenAddr::Tcp(addr) => bind_all(addr, tcp_listener)
+                .map(Self::Tcp)
+                .map_err(Into::into),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // remove stale socket file left by previous process. any other file at the path
+                // is left untouched.
+                match std::fs::symlink_metadata(&path) {
+                    Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(&path)?,
+                    Ok(_) => {
+                        return Err(Error::from(format!(
+                            "{} exists and is not a unix socket",
+                            path.display()
+                        )))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+                tokio::net::UnixListener::bind(path)
+                    .map(Self::Unix)
+                    .map_err(Into::into)
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(Error::from(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix domain socket is not supported on this platform",
+            ))),
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        match self {
+            Self::Tcp(listeners) => listeners.len(),
+            #[cfg(unix)]
+            Self::Unix(_) => 1,
+        }
+    }
+}
+
+// source of client address of DoH request.
+#[derive(Clone, Copy)]
+pub(super) enum ClientAddr {
+    // address of connection peer.
+    Peer(IpAddr),
+    // address forwarded by reverse proxy through request headers. connection peer address is
+    // used when headers are absent.
+    Forwarded(Option<IpAddr>),
+}
+
+impl ClientAddr {
+    fn resolve(self, headers: &HeaderMap) -> Option<IpAddr> {
+        match self {
+            Self::Peer(addr) => Some(addr),
+            Self::Forwarded(addr) => forwarded_for(headers).or(addr),
+        }
+    }
+}
+
+impl App {
+    pub(super) async fn run_https(self: Arc<Self>, idx: usize) -> Result<(), Error> {
+        let listener = self.https_listener.as_ref().unwrap();
+        self.clone()
+            .run_tls_listener(listener, idx, |this, stream, addr| async move {
+                let io = xitca_io::io::PollIoAdapter(stream);
+                this.serve_http(io, ClientAddr::Peer(addr.ip())).await
+            })
+            .await
+    }
+
+    pub(super) async fn run_http(self: Arc<Self>, idx: usize) -> Result<(), Error> {
+        match self.http_listener.as_ref().unwrap() {
+            HttpListener::Tcp(listeners) => loop {
+                match listeners[idx].accept().await {
+                    Ok((stream, addr)) => {
+                        let _ = stream.set_nodelay(true);
+                        self.spawn_http(stream, Some(addr));
+                    }
+                    Err(ref e) if connection_error(e) => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            },
+            #[cfg(unix)]
+            HttpListener::Unix(listener) => loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => self.spawn_http(stream, None),
+                    Err(ref e) if connection_error(e) => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            },
+        }
+    }
+
+    fn spawn_http<Io>(self: &Arc<Self>, io: Io, addr: Option<SocketAddr>)
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let this = self.clone();
+        self.tasks.spawn(async move {
+            let client = ClientAddr::Forwarded(addr.map(|addr| addr.ip()));
+            if let Err(e) = this.serve_http(io, client).await {
+                trace!("{addr:?} connection error: {e}");
+            }
+        });
+    }
+
+    // serve DoH on http/1 and http/2 connection. RFC 8484
+    pub(super) async fn serve_http<Io>(
+        self: Arc<Self>,
+        io: Io,
+        client: ClientAddr,
+    ) -> Result<(), Error>
+    where
+        Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let service = service_fn(|req: Request<Incoming>| {
+            let this = self.clone();
+            let client = client.resolve(req.headers());
+            async move { Ok::<_, Infallible>(this.dns_query(req, client).await) }
+        });
+
+        let builder = auto::Builder::new(TokioExecutor::new());
+        let conn = builder.serve_connection(TokioIo::new(io), service);
+        let mut conn = pin!(conn);
+
+        // finish in-flight requests and close connection on shutdown.
+        match self.shutdown.run_until_cancelled(conn.as_mut()).await {
+            Some(res) => res,
+            None => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        }
+    }
+
+    async fn dns_query(
+        &self,
+        req: Request<Incoming>,
+        client: Option<IpAddr>,
+    ) -> Response<Full<Bytes>> {
+        if req.uri().path() != PATH {
+            return status(StatusCode::NOT_FOUND);
+        }
+
+        let mut buf = match *req.method() {
+            Method::GET => match query_param(req.uri()) {
+                Some(buf) => buf,
+                None => return status(StatusCode::BAD_REQUEST),
+            },
+            Method::POST => {
+                if req.headers().get(CONTENT_TYPE) != Some(&DNS_MSG_HDR) {
+                    return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                }
+                match Limited::new(req.into_body(), MAX_MSG_LEN).collect().await {
+                    Ok(body) => body.to_bytes().to_vec(),
+                    Err(_) => return status(StatusCode::BAD_REQUEST),
+                }
+            }
+            _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+        };
+
+        let Some(either) = self.lookup(&mut buf, MAX_MSG_LEN) else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+
+        // upstream failure is answered with SERVFAIL message instead of http error status.
+        let mut buf = self.resolve(either, client).await;
+
+        let max_age = min_ttl(&mut buf);
+        let mut res = Response::new(Full::new(Bytes::from(buf)));
+        res.headers_mut().insert(CONTENT_TYPE, DNS_MSG_HDR.clone());
+        if let Some(ttl) = max_age {
+            let value = HeaderValue::from_str(&format!("max-age={ttl}")).unwrap();
+            res.headers_mut().insert(CACHE_CONTROL, value);
+        }
+        res
+    }
+}
+
+// decode base64url encoded dns query from dns parameter of uri.
+fn query_param(uri: &Uri) -> Option<Vec<u8>> {
+    let value = uri
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("dns="))?;
+    URL_SAFE_NO_PAD.decode(value.trim_end_matches('=')).ok()
+}
+
+// client address from the first hop of Forwarded or X-Forwarded-For header. RFC 7239
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    if let Some(value) = headers.get(FORWARDED).and_then(|v| v.to_str().ok()) {
+        let node = value.split(',').next()?.split(';').find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            key.eq_ignore_ascii_case("for").then_some(value)
+        })?;
+        return parse_node(node);
+    }
+
+    let value = headers.get("x-forwarded-for")?.to_str().ok()?;
+    parse_node(value.split(',').next()?)
+}
+
+// parse ip address from forwarded node in the form of ip, "ip:port" or "[ipv6]:port".
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .or_else(|| node.strip_prefix('[')?.split(']').next()?.parse().ok())
+}
+
+// minimum ttl of answers. cache freshness of response must not exceed it.
+fn min_ttl(buf: &mut [u8]) -> Option<u32> {
+    let mut packet = Packet::new();
+    packet.read(&mut Buf::new(buf)).ok()?;
+    packet.answers.iter().map(Answer::ttl).min()
+}
+
+fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::default());
+    *res.status_mut() = status;
+    res
+}
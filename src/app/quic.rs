@@ -0,0 +1,124 @@
+use core::net::{IpAddr, SocketAddr};
+
+use std::{io, sync::Arc};
+
+use quinn::{
+    crypto::rustls::QuicServerConfig, ConnectionError, Endpoint, EndpointConfig, Incoming,
+    RecvStream, SendStream, ServerConfig, TokioRuntime,
+};
+use socket2::{Protocol, Type};
+use tracing::trace;
+
+use crate::{error::Error, proxy::quic::MAX_LEN};
+
+use super::{bind_all, socket, App, MAX_MSG_LEN};
+
+pub(super) struct QuicListener {
+    // one endpoint per resolved address.
+    endpoints: Vec<Endpoint>,
+}
+
+impl QuicListener {
+    pub(super) fn bind(
+        addr: Vec<SocketAddr>,
+        cfg: Arc<rustls::ServerConfig>,
+    ) -> Result<Self, Error> {
+        let cfg = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(cfg)?));
+        let endpoints = bind_all(addr, |addr| {
+            let udp = socket(addr, Type::DGRAM, Protocol::UDP)?;
+            udp.bind(&addr.into())?;
+            Endpoint::new(
+                EndpointConfig::default(),
+                Some(cfg.clone()),
+                udp.into(),
+                Arc::new(TokioRuntime),
+            )
+        })?;
+        Ok(Self { endpoints })
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    pub(super) async fn close(&self) {
+        for endpoint in self.endpoints.iter() {
+            endpoint.close(0u32.into(), b"");
+        }
+        for endpoint in self.endpoints.iter() {
+            endpoint.wait_idle().await;
+        }
+    }
+}
+
+impl App {
+    pub(super) async fn run_quic(self: Arc<Self>, idx: usize) -> Result<(), Error> {
+        let endpoint = &self.quic_listener.as_ref().unwrap().endpoints[idx];
+
+        while let Some(incoming) = endpoint.accept().await {
+            let this = self.clone();
+            self.tasks.spawn(async move {
+                let addr = incoming.remote_address();
+                if let Err(e) = this.serve_quic(incoming).await {
+                    trace!("{addr} connection error: {e}");
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn serve_quic(self: Arc<Self>, incoming: Incoming) -> Result<(), Error> {
+        let conn = incoming.await?;
+        let client = conn.remote_address().ip();
+
+        loop {
+            // stop accepting new stream on shutdown. connection is closed when in-flight streams
+            // are finished.
+            let (send, recv) = match self.shutdown.run_until_cancelled(conn.accept_bi()).await {
+                Some(Ok(stream)) => stream,
+                Some(Err(ConnectionError::ApplicationClosed(_)))
+                | Some(Err(ConnectionError::LocallyClosed))
+                | Some(Err(ConnectionError::TimedOut))
+                | None => return Ok(()),
+                Some(Err(e)) => return Err(e.into()),
+            };
+
+            let this = self.clone();
+            self.tasks.spawn(async move {
+                if let Err(e) = this.serve_quic_stream(send, recv, client).await {
+                    trace!("stream error: {e}");
+                }
+            });
+        }
+    }
+
+    // every bidirectional stream carries a single query and response with two bytes length
+    // prefix. RFC 9250 4.2
+    async fn serve_quic_stream(
+        &self,
+        mut send: SendStream,
+        mut recv: RecvStream,
+        client: IpAddr,
+    ) -> Result<(), Error> {
+        let buf = recv.read_to_end(MAX_LEN).await?;
+
+        let (len, buf) = match buf.split_first_chunk::<2>() {
+            Some((len, buf)) => (u16::from_be_bytes(*len) as usize, buf),
+            None => return Err(Error::from(io::Error::from(io::ErrorKind::UnexpectedEof))),
+        };
+
+        let mut buf = buf.get(..len).unwrap_or(buf).to_vec();
+
+        let Some(either) = self.lookup(&mut buf, MAX_MSG_LEN) else {
+            return Err(Error::from(io::Error::from(io::ErrorKind::InvalidData)));
+        };
+        let res = self.resolve(either, Some(client)).await;
+
+        send.write_all(&(res.len() as u16).to_be_bytes()).await?;
+        send.write_all(&res).await?;
+        send.finish()?;
+
+        Ok(())
+    }
+}
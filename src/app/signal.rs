@@ -0,0 +1,63 @@
+use std::{io, sync::Arc};
+
+use tokio::task::JoinSet;
+use tracing::{error, info};
+
+use crate::error::Error;
+
+use super::App;
+
+// spawn tasks to set that exit on shutdown signal. SIGTERM and SIGINT on unix and ctrl-c on other
+// platforms.
+pub(super) fn shutdown(set: &mut JoinSet<Result<(), Error>>) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        for (kind, name) in [
+            (SignalKind::terminate(), "SIGTERM"),
+            (SignalKind::interrupt(), "SIGINT"),
+        ] {
+            let mut signal = signal(kind)?;
+            set.spawn(async move {
+                signal.recv().await;
+                info!("received shutdown signal: {name}");
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    set.spawn(async {
+        tokio::signal::ctrl_c().await?;
+        info!("received shutdown signal: ctrl-c");
+        Ok(())
+    });
+
+    Ok(())
+}
+
+// spawn task to set that reloads configuration on SIGHUP. no op on other platforms.
+pub(super) fn reload(set: &mut JoinSet<Result<(), Error>>, app: Arc<App>) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signal = signal(SignalKind::hangup())?;
+        set.spawn(async move {
+            while signal.recv().await.is_some() {
+                info!("received reload signal: SIGHUP");
+                if let Err(e) = app.reload().await {
+                    error!("configuration reload rejected: {e}");
+                }
+            }
+            // reload task must not exit as it would be treated as shutdown request.
+            core::future::pending().await
+        });
+    }
+
+    #[cfg(not(unix))]
+    let _ = (set, app);
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use core::{net::IpAddr, time::Duration};
+
+use std::{io, sync::Arc};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, Semaphore},
+    time::timeout,
+};
+use tracing::trace;
+
+use crate::error::Error;
+
+use super::{connection_error, App, MAX_MSG_LEN};
+
+// a connection without new query for this duration would be closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// max number of queries can be in-flight for a single connection. when reached the connection
+// would stop reading new query until a response is sent.
+const MAX_IN_FLIGHT: usize = 32;
+
+impl App {
+    pub(super) async fn run_tcp(self: Arc<Self>, idx: usize) -> Result<(), Error> {
+        loop {
+            match self.listeners[idx].tcp.accept().await {
+                Ok((stream, addr)) => {
+                    let _ = stream.set_nodelay(true);
+                    let this = self.clone();
+                    self.tasks.spawn(async move {
+                        if let Err(e) = this.serve_stream(stream, addr.ip()).await {
+                            trace!("{addr} connection error: {e}");
+                        }
+                    });
+                }
+                Err(ref e) if connection_error(e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // serve dns queries from a stream with two bytes length prefix framing. RFC 7766
+    // queries are resolved concurrently and responses are written back in the order they are
+    // resolved.
+    pub(super) async fn serve_stream<Io>(self: Arc<Self>, io: Io, client: IpAddr) -> io::Result<()>
+    where
+        Io: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut rd, mut wr) = tokio::io::split(io);
+
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MAX_IN_FLIGHT);
+
+        let write = tokio::spawn(async move {
+            while let Some(buf) = rx.recv().await {
+                wr.write_all(&(buf.len() as u16).to_be_bytes()).await?;
+                wr.write_all(&buf).await?;
+            }
+            wr.shutdown().await
+        });
+
+        let permits = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+
+        loop {
+            let permit = permits.clone().acquire_owned().await.unwrap();
+
+            // stop reading new query on shutdown. in-flight queries are still answered.
+            let read = timeout(IDLE_TIMEOUT, rd.read_u16());
+            let len = match self.shutdown.run_until_cancelled(read).await {
+                Some(Ok(Ok(len))) => len,
+                Some(Ok(Err(e))) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Some(Ok(Err(e))) => return Err(e),
+                Some(Err(_)) | None => break,
+            };
+
+            let mut buf = vec![0; len as usize];
+
+            match timeout(IDLE_TIMEOUT, rd.read_exact(&mut buf)).await {
+                Ok(res) => res?,
+                Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+            };
+
+            let Some(either) = self.lookup(&mut buf, MAX_MSG_LEN) else {
+                continue;
+            };
+            let this = self.clone();
+            let tx = tx.clone();
+            self.tasks.spawn(async move {
+                let buf = this.resolve(either, Some(client)).await;
+                let _ = tx.send(buf).await;
+                drop(permit);
+            });
+        }
+
+        // drop sender so writer task can finish after all in-flight queries are resolved.
+        drop(tx);
+
+        write.await.unwrap()
+    }
+}
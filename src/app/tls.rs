@@ -0,0 +1,92 @@
+use core::{future::Future, net::SocketAddr, time::Duration};
+
+use std::sync::Arc;
+
+use tokio::{net::TcpListener, time::timeout};
+use tracing::trace;
+use xitca_io::net::TcpStream;
+use xitca_tls::rustls::{ServerConfig, ServerConnection};
+
+use crate::error::Error;
+
+use super::{bind_all, connection_error, tcp_listener, App};
+
+pub(super) type TlsStream = xitca_tls::rustls::TlsStream<ServerConnection, TcpStream>;
+
+// max duration for tls handshake of a new connection.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(super) struct TlsListener {
+    // one listener per resolved address.
+    listeners: Vec<TcpListener>,
+    cfg: Arc<ServerConfig>,
+}
+
+impl TlsListener {
+    pub(super) fn bind(addr: Vec<SocketAddr>, cfg: Arc<ServerConfig>) -> Result<Self, Error> {
+        let listeners = bind_all(addr, tcp_listener)?;
+        Ok(Self { listeners, cfg })
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.listeners.len()
+    }
+}
+
+impl App {
+    #[cfg(feature = "tls")]
+    pub(super) async fn run_tls(self: Arc<Self>, idx: usize) -> Result<(), Error> {
+        let listener = self.tls_listener.as_ref().unwrap();
+        self.clone()
+            .run_tls_listener(listener, idx, |this, stream, addr| async move {
+                let io = xitca_io::io::PollIoAdapter(stream);
+                this.serve_stream(io, addr.ip()).await.map_err(Into::into)
+            })
+            .await
+    }
+
+    // accept tls connections from listener of given index and pass them to service after
+    // handshake.
+    pub(super) async fn run_tls_listener<F, Fut>(
+        self: Arc<Self>,
+        listener: &TlsListener,
+        idx: usize,
+        service: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(Arc<Self>, TlsStream, SocketAddr) -> Fut + Copy + Send + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        loop {
+            match listener.listeners[idx].accept().await {
+                Ok((stream, addr)) => {
+                    let this = self.clone();
+                    let cfg = listener.cfg.clone();
+                    self.tasks.spawn(async move {
+                        let res = async move {
+                            let stream = handshake(stream, cfg).await?;
+                            service(this, stream, addr).await
+                        };
+                        if let Err(e) = res.await {
+                            trace!("{addr} connection error: {e}");
+                        }
+                    });
+                }
+                Err(ref e) if connection_error(e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+async fn handshake(
+    stream: tokio::net::TcpStream,
+    cfg: Arc<ServerConfig>,
+) -> Result<TlsStream, Error> {
+    let _ = stream.set_nodelay(true);
+    let stream = TcpStream::from_std(stream.into_std()?)?;
+    let conn = ServerConnection::new(cfg)?;
+    timeout(HANDSHAKE_TIMEOUT, TlsStream::handshake(stream, conn))
+        .await?
+        .map_err(Into::into)
+}
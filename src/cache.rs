@@ -9,7 +9,7 @@ use std::{
 use tokio::task::JoinHandle;
 use tracing::trace;
 
-use crate::dns::{Answer, Buf, Packet, Question};
+use crate::dns::{Answer, Buf, Packet, Question, ResultCode};
 
 /// a simple cache just use query bytes and result bytes as key value pair.
 pub struct Cache {
@@ -52,9 +52,14 @@ impl Cache {
         }
     }
 
+    // only NOERROR response with answers is cached. cached entry is encoded as NOERROR response
+    // and expires by ttl of answers so negative and error responses can't be kept.
     pub fn set(&self, buf: &mut [u8]) {
         let mut packet = Packet::new();
-        if packet.read(&mut Buf::new(buf)).is_ok() {
+        if packet.read(&mut Buf::new(buf)).is_ok()
+            && packet.header.rescode == ResultCode::NOERROR
+            && !packet.answers.is_empty()
+        {
             let questions = packet.questions.into_boxed_slice();
             trace!("updating/creating cache record: {questions:?}");
             self.inner.write().unwrap().insert(
@@ -64,7 +69,8 @@ impl Cache {
         }
     }
 
-    pub fn get(&self, buf: &mut [u8]) -> Option<Vec<u8>> {
+    // encode cached answers as response to query. response can not exceed limit.
+    pub fn get(&self, buf: &mut [u8], limit: usize) -> Option<Vec<u8>> {
         let mut packet = Packet::new_ref();
 
         packet.read(&mut Buf::new(buf)).ok()?;
@@ -80,14 +86,35 @@ impl Cache {
 
         let answers = entry.answers();
         trace!("got cache records: {answers:?}");
+        packet.set_response();
         packet.answers = answers;
 
-        let mut buf = vec![0; 512];
+        // most responses fit in a udp message. buffer only grows up to limit when they don't.
+        let mut buf = vec![0; limit.min(512)];
+        loop {
+            let dns_buf = &mut Buf::new(&mut buf);
+            if packet.write(dns_buf).is_ok() {
+                let len = dns_buf.pos;
+                buf.truncate(len);
+                return Some(buf);
+            }
+            if buf.len() == limit {
+                break;
+            }
+            let len = (buf.len() * 4).min(limit);
+            buf.resize(len, 0);
+        }
+
+        // answers don't fit. respond with questions only and TC flag so client can retry
+        // over tcp. RFC 2181 9
+        packet.header.truncated_message = true;
+        packet.answers = &[];
         let dns_buf = &mut Buf::new(&mut buf);
-        packet.write(dns_buf).ok().map(|_| {
-            buf.truncate(dns_buf.pos);
-            buf
-        })
+        packet.write(dns_buf).ok()?;
+
+        let len = dns_buf.pos;
+        buf.truncate(len);
+        Some(buf)
     }
 }
 
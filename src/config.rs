@@ -1,23 +1,359 @@
 /// Argument parsing.
-use core::{net::SocketAddr, str::FromStr};
+use core::{fmt, net::SocketAddr, str::FromStr, time::Duration};
 
-use std::net::ToSocketAddrs;
+use std::{io, net::ToSocketAddrs, path::PathBuf};
 
-use bpaf::{construct, short, Parser};
+use bpaf::{construct, short, Args, OptionParser, ParseFailure, Parser};
 use tracing::Level;
 
+use crate::{dns::ResultCode, error::Error};
+
+use self::file::File;
+
+mod file;
+
 #[derive(Debug)]
 pub struct Config {
     pub listen_addr: Vec<SocketAddr>,
-    pub upstream_addr: Vec<UpstreamVariant>,
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    pub tls_listen_addr: Vec<SocketAddr>,
+    #[cfg_attr(not(feature = "https"), allow(dead_code))]
+    pub https_listen_addr: Vec<SocketAddr>,
+    #[cfg_attr(not(feature = "https"), allow(dead_code))]
+    pub http_listen_addr: Option<ListenAddr>,
+    #[cfg_attr(not(feature = "quic"), allow(dead_code))]
+    pub quic_listen_addr: Vec<SocketAddr>,
+    #[cfg_attr(
+        not(any(feature = "tls", feature = "https", feature = "quic")),
+        allow(dead_code)
+    )]
+    pub cert: Option<PathBuf>,
+    #[cfg_attr(
+        not(any(feature = "tls", feature = "https", feature = "quic")),
+        allow(dead_code)
+    )]
+    pub key: Option<PathBuf>,
+    // default group of upstreams for queries not matched by any route.
+    pub upstream: UpstreamGroup,
+    // named groups of upstreams routes forward queries to.
+    pub groups: Vec<(String, UpstreamGroup)>,
+    pub routes: Vec<Route>,
     pub boot_strap_addr: Vec<SocketAddr>,
+    pub timeout: Duration,
     pub log_level: Level,
     pub thread_count: Option<usize>,
 }
 
+pub enum Command {
+    // run proxy.
+    Run(Config),
+    // validate configuration and exit.
+    Check(Config),
+}
+
+// print configuration in the format of config file.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(thread) = self.thread_count {
+            writeln!(f, "thread = {thread}\n")?;
+        }
+
+        writeln!(f, "[listen]")?;
+        writeln!(f, "addr = {}", List(&self.listen_addr))?;
+        #[cfg(feature = "tls")]
+        writeln!(f, "tls = {}", List(&self.tls_listen_addr))?;
+        #[cfg(feature = "https")]
+        writeln!(f, "https = {}", List(&self.https_listen_addr))?;
+        #[cfg(feature = "https")]
+        if let Some(ref addr) = self.http_listen_addr {
+            writeln!(f, "http = \"{addr}\"")?;
+        }
+        #[cfg(feature = "quic")]
+        writeln!(f, "quic = {}", List(&self.quic_listen_addr))?;
+        if let Some(ref cert) = self.cert {
+            writeln!(f, "cert = {:?}", cert.display().to_string())?;
+        }
+        if let Some(ref key) = self.key {
+            writeln!(f, "key = {:?}", key.display().to_string())?;
+        }
+
+        writeln!(f, "\n[upstream]")?;
+        write!(f, "{}", self.upstream)?;
+        writeln!(f, "bootstrap = {}", List(&self.boot_strap_addr))?;
+
+        for (name, group) in self.groups.iter() {
+            writeln!(f, "\n[group.{name}]")?;
+            write!(f, "{group}")?;
+        }
+
+        if !self.routes.is_empty() {
+            writeln!(f, "\n[route]")?;
+            let names = self.groups.iter().map(|(name, _)| name.as_str());
+            for name in core::iter::once(DEFAULT_GROUP).chain(names) {
+                let suffix = self
+                    .routes
+                    .iter()
+                    .filter(|route| route.group == name)
+                    .map(|route| route.suffix.as_str())
+                    .collect::<Vec<_>>();
+                if !suffix.is_empty() {
+                    writeln!(f, "{name} = {}", List(&suffix))?;
+                }
+            }
+        }
+
+        writeln!(f, "\n[policy]")?;
+        writeln!(f, "timeout = {}", self.timeout.as_millis())?;
+
+        writeln!(f, "\n[log]")?;
+        writeln!(f, "level = \"{}\"", self.log_level.as_str().to_lowercase())
+    }
+}
+
+impl fmt::Display for UpstreamGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "addr = {}", List(&self.addr))?;
+        writeln!(f, "strategy = \"{}\"", self.strategy)?;
+        if let Some(hedge) = self.hedge {
+            writeln!(f, "hedge = \"{hedge}\"")?;
+        }
+        writeln!(f, "fallback = \"{}\"", self.fallback)?;
+        if let Some(timeout) = self.timeout {
+            writeln!(f, "timeout = {}", timeout.as_millis())?;
+        }
+        writeln!(f, "retries = {}", self.retries)?;
+        #[cfg(feature = "tls")]
+        {
+            writeln!(f, "tls_connections = {}", self.tls_pool.size)?;
+            writeln!(f, "tls_warm_connections = {}", self.tls_pool.warm)?;
+        }
+        Ok(())
+    }
+}
+
+// toml array of strings.
+struct List<'a, T>(&'a [T]);
+
+impl<T: fmt::Display> fmt::Display for List<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        for (i, item) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "\"{item}\"")?;
+        }
+        f.write_str("]")
+    }
+}
+
+// values from command line arguments. absent ones are taken from config file and default
+// values after.
+struct CliArgs {
+    config: Option<PathBuf>,
+    listen_addr: Vec<SocketAddr>,
+    tls_listen_addr: Option<Vec<SocketAddr>>,
+    https_listen_addr: Option<Vec<SocketAddr>>,
+    http_listen_addr: Option<ListenAddr>,
+    quic_listen_addr: Option<Vec<SocketAddr>>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    upstream_addr: Vec<Upstream>,
+    strategy: Option<Strategy>,
+    hedge: Option<Hedge>,
+    fallback: Option<Fallback>,
+    upstream_timeout: Option<u64>,
+    retries: Option<u32>,
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    tls_connections: Option<usize>,
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    tls_warm_connections: Option<usize>,
+    boot_strap_addr: Option<Vec<SocketAddr>>,
+    timeout: Option<u64>,
+    log_level: Option<Level>,
+    thread_count: Option<usize>,
+}
+
+#[cold]
+#[inline(never)]
+pub fn parse_arg() -> Result<Command, Error> {
+    let (check, args) = options().run();
+    let cfg = load(args)?;
+    Ok(match check {
+        true => Command::Check(cfg),
+        false => Command::Run(cfg),
+    })
+}
+
+// parse arguments and config file again for configuration reload. error is returned instead of
+// exiting process.
 #[cold]
 #[inline(never)]
-pub fn parse_arg() -> Config {
+pub fn reparse_arg() -> Result<Config, Error> {
+    let args = options()
+        .run_inner(Args::current_args())
+        .map_err(|e| match e {
+            ParseFailure::Stderr(doc) => Error::from(doc.monochrome(false)),
+            _ => Error::from("unexpected argument for configuration reload"),
+        })?;
+    load(args.1)
+}
+
+// merge command line arguments with config file. arguments take precedence over file.
+fn load(args: CliArgs) -> Result<Config, Error> {
+    let file = match args.config {
+        Some(ref path) => File::read(path)?,
+        None => File::default(),
+    };
+
+    let mut listen_addr = match args.listen_addr.is_empty() {
+        true => file.listen.addr.unwrap_or_default(),
+        false => args.listen_addr,
+    };
+    // binding the same address twice would fail.
+    listen_addr.sort();
+    listen_addr.dedup();
+    if listen_addr.is_empty() {
+        listen_addr.push(SocketAddr::from(([0u8; 4], 53)));
+    }
+
+    let upstream_addr = match args.upstream_addr.is_empty() {
+        true => file.upstream.addr.unwrap_or_default(),
+        false => args.upstream_addr,
+    };
+    if upstream_addr.is_empty() {
+        return Err(Error::from(
+            "At least one upstream dns server is needed from --upstream argument or upstream.addr of config file",
+        ));
+    }
+
+    let boot_strap_addr = match args.boot_strap_addr.or(file.upstream.bootstrap) {
+        Some(addr) => addr,
+        None => "1.1.1.1:53".to_socket_addrs().map(Vec::from_iter)?,
+    };
+    if boot_strap_addr.is_empty() {
+        return Err(Error::from("bootstrap dns address must not be empty"));
+    }
+
+    let timeout = Duration::from_millis(args.timeout.or(file.policy.timeout).unwrap_or(3000));
+
+    // groups only come from config file.
+    let groups = file
+        .group
+        .into_iter()
+        .map(|(name, group)| {
+            let name = name.0;
+            let group = UpstreamGroup {
+                addr: group.addr,
+                strategy: group.strategy.unwrap_or_default(),
+                hedge: group.hedge,
+                fallback: group.fallback.unwrap_or_default(),
+                timeout: group.timeout.map(Duration::from_millis),
+                retries: group.retries.unwrap_or(0),
+                #[cfg(feature = "tls")]
+                tls_pool: TlsPool::new(
+                    group.tls_connections,
+                    group.tls_warm_connections.map(toml::Spanned::into_inner),
+                )
+                .map_err(|e| format!("group.{name}: {e}"))?,
+            };
+            Ok((name, group))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let routes = file
+        .route
+        .into_iter()
+        .flat_map(|(group, suffix)| {
+            let group = group.into_inner();
+            suffix.into_iter().map(move |suffix| Route {
+                suffix: suffix.0,
+                group: group.clone(),
+            })
+        })
+        .collect();
+
+    Ok(Config {
+        listen_addr,
+        tls_listen_addr: args.tls_listen_addr.or(file.listen.tls).unwrap_or_default(),
+        https_listen_addr: args
+            .https_listen_addr
+            .or(file.listen.https)
+            .unwrap_or_default(),
+        http_listen_addr: args.http_listen_addr.or(file.listen.http),
+        quic_listen_addr: args
+            .quic_listen_addr
+            .or(file.listen.quic)
+            .unwrap_or_default(),
+        cert: args.cert.or(file.listen.cert),
+        key: args.key.or(file.listen.key),
+        upstream: UpstreamGroup {
+            addr: upstream_addr,
+            strategy: args.strategy.or(file.upstream.strategy).unwrap_or_default(),
+            hedge: args.hedge.or(file.upstream.hedge),
+            fallback: args.fallback.or(file.upstream.fallback).unwrap_or_default(),
+            timeout: args
+                .upstream_timeout
+                .or(file.upstream.timeout)
+                .map(Duration::from_millis),
+            retries: args.retries.or(file.upstream.retries).unwrap_or(0),
+            #[cfg(feature = "tls")]
+            tls_pool: TlsPool::new(
+                args.tls_connections.or(file.upstream.tls_connections),
+                args.tls_warm_connections.or(file
+                    .upstream
+                    .tls_warm_connections
+                    .map(toml::Spanned::into_inner)),
+            )?,
+        },
+        groups,
+        routes,
+        boot_strap_addr,
+        timeout,
+        log_level: args.log_level.or(file.log.level).unwrap_or(Level::INFO),
+        thread_count: args.thread_count.or(file.thread),
+    })
+}
+
+// normalize domain suffix of route to lower case without leading wildcard label, leading and
+// trailing dot. "." or empty string is the root and matches every name.
+fn route_suffix(suffix: &str) -> Option<String> {
+    let suffix = suffix.strip_prefix("*.").unwrap_or(suffix);
+    let suffix = suffix.strip_prefix('.').unwrap_or(suffix);
+    let suffix = suffix.strip_suffix('.').unwrap_or(suffix);
+    if suffix.is_empty() {
+        return Some(String::new());
+    }
+    // rfc 1035 section 2.3.4. wildcard is only allowed as the leading label.
+    if suffix.len() > 253
+        || suffix
+            .split('.')
+            .any(|l| l.is_empty() || l.len() > 63 || l.contains('*'))
+    {
+        return None;
+    }
+    Some(suffix.to_ascii_lowercase())
+}
+
+// check-config subcommand accepts the same arguments and flags it with true.
+fn options() -> OptionParser<(bool, CliArgs)> {
+    let check = args()
+        .map(|args| (true, args))
+        .to_options()
+        .descr("Validate configuration and print it in normalized form without binding listeners or connecting to upstreams. exit with non zero code on any problem")
+        .command("check-config");
+    let run = args().map(|args| (false, args));
+    construct!([check, run]).to_options()
+}
+
+fn args() -> impl Parser<CliArgs> {
+    let config = bpaf::long("config")
+        .help(
+            "Path to TOML config file. command line arguments take precedence over values from it",
+        )
+        .argument::<PathBuf>("CONFIG")
+        .optional();
+
     let thread_count = short('t')
         .long("thread")
         .help("OS thread count dns-proxy would spawn and opperate on in parallel")
@@ -26,48 +362,492 @@ pub fn parse_arg() -> Config {
 
     let listen_addr = short('l')
         .long("listen")
-        .help("Local listening address for proxy")
+        .help("Local listening address for proxy. can be used multiple times and proxy would listen on all resolved addresses")
         .argument::<String>("LISTEN")
-        .fallback_with::<_, String>(|| Ok("0.0.0.0:53".to_owned()))
-        .parse(|addr| addr.to_socket_addrs().map(Vec::from_iter));
+        .parse(|addr| addr.to_socket_addrs().map(Vec::from_iter))
+        .many()
+        .map(|addrs| addrs.into_iter().flatten().collect());
+
+    #[cfg(feature = "tls")]
+    let tls_listen_addr = bpaf::long("tls-listen")
+        .help("Local listening address for DoT. port 853 is used when it's not specified")
+        .argument::<String>("TLS_LISTEN")
+        .parse(|addr| resolve_addr(&addr, 853))
+        .optional();
+    #[cfg(not(feature = "tls"))]
+    let tls_listen_addr = bpaf::pure(None);
+
+    #[cfg(feature = "https")]
+    let https_listen_addr = bpaf::long("https-listen")
+        .help("Local listening address for DoH. port 443 is used when it's not specified")
+        .argument::<String>("HTTPS_LISTEN")
+        .parse(|addr| resolve_addr(&addr, 443))
+        .optional();
+    #[cfg(not(feature = "https"))]
+    let https_listen_addr = bpaf::pure(None);
+
+    #[cfg(feature = "https")]
+    let http_listen_addr = bpaf::long("http-listen")
+        .help("Local listening address for cleartext DoH behind reverse proxy. unix domain socket can be used with unix: prefixed path and only an existing socket file is replaced. client address is taken from Forwarded or X-Forwarded-For header. port 80 is used when it's not specified")
+        .argument::<ListenAddr>("HTTP_LISTEN")
+        .optional();
+    #[cfg(not(feature = "https"))]
+    let http_listen_addr = bpaf::pure(None);
+
+    #[cfg(feature = "quic")]
+    let quic_listen_addr = bpaf::long("quic-listen")
+        .help("Local listening address for DoQ. port 853 is used when it's not specified")
+        .argument::<String>("QUIC_LISTEN")
+        .parse(|addr| resolve_addr(&addr, 853))
+        .optional();
+    #[cfg(not(feature = "quic"))]
+    let quic_listen_addr = bpaf::pure(None);
+
+    #[cfg(any(feature = "tls", feature = "https", feature = "quic"))]
+    let cert = short('c')
+        .long("cert")
+        .help("Path to PEM encoded certificate chain for DoT/DoH/DoQ listener")
+        .argument::<PathBuf>("CERT")
+        .optional();
+    #[cfg(not(any(feature = "tls", feature = "https", feature = "quic")))]
+    let cert = bpaf::pure(None);
+
+    #[cfg(any(feature = "tls", feature = "https", feature = "quic"))]
+    let key = short('k')
+        .long("key")
+        .help("Path to PEM encoded private key for DoT/DoH/DoQ listener")
+        .argument::<PathBuf>("KEY")
+        .optional();
+    #[cfg(not(any(feature = "tls", feature = "https", feature = "quic")))]
+    let key = bpaf::pure(None);
 
     let upstream_addr = short('u')
         .long("upstream")
-        .help("Upstream server for dns look up")
-        .argument::<UpstreamVariant>("UPSTREAM")
-        .some("--upstream argument must not be empty. At least one upstream dns server is needed");
+        .help("Upstream server for dns look up. can be used multiple times and queries would be spread across all of them. tcp:// prefixed upstream is queried over tcp. options can be suffixed as 8.8.8.8:53@2,timeout=500,retries=1 where bare number is weight for random strategy and timeout in milliseconds and retries override --upstream-timeout and --retries for the upstream")
+        .argument::<Upstream>("UPSTREAM")
+        .many();
+
+    let strategy = bpaf::long("strategy")
+        .help("Load balancing strategy of upstreams: round-robin,random,latency,race. random picks upstream by weight given with @ suffixed upstream, latency picks upstream with the lowest average response time and race queries all upstreams in parallel for the fastest answer. round-robin is used when it's not specified")
+        .argument::<Strategy>("STRATEGY")
+        .optional();
+
+    let hedge = bpaf::long("hedge")
+        .help("Delay in milliseconds before a query not answered by upstream is sent to the next upstream as well. the first answer from either of them is used. p95 uses the 95th percentile response time learned from each upstream. hedging is disabled when it's not specified")
+        .argument::<Hedge>("HEDGE")
+        .optional();
+
+    let fallback = bpaf::long("fallback")
+        .help("Result codes of upstream response retried with the next upstream: servfail,refused,notimp or none. failed or timed out query is retried with the next upstream unless it's none. race and hedged queries always prefer NOERROR or NXDOMAIN response. servfail,refused is used when it's not specified")
+        .argument::<Fallback>("FALLBACK")
+        .optional();
+
+    let upstream_timeout = bpaf::long("upstream-timeout")
+        .help("Deadline in milliseconds for each attempt of querying an upstream. it applies to every upstream of the group without timeout= option. --timeout divided by the number of attempts(--retries + 1) is used when it's not specified. the number is doubled when failed or timed out query falls back to the next upstream so fallback is not starved")
+        .argument::<u64>("UPSTREAM_TIMEOUT")
+        .optional();
+
+    let retries = bpaf::long("retries")
+        .help("Times a query is sent to the same upstream again after it fails or times out. it applies to every upstream of the group without retries= option. 0 is used when it's not specified")
+        .argument::<u32>("RETRIES")
+        .optional();
+
+    #[cfg(feature = "tls")]
+    let tls_connections = bpaf::long("tls-connections")
+        .help("Pipelined connections to each DoT upstream. query is sent on the least loaded open one and the rest of connections are used when every open one has 32 queries in flight. 1 is used when it's not specified")
+        .argument::<usize>("TLS_CONNECTIONS")
+        .optional();
+    #[cfg(not(feature = "tls"))]
+    let tls_connections = bpaf::pure(None);
+
+    #[cfg(feature = "tls")]
+    let tls_warm_connections = bpaf::long("tls-warm-connections")
+        .help("Connections to each DoT upstream kept open when there is no query. the rest are made when they are needed and closed after 10 seconds without query. 1 is used when it's not specified")
+        .argument::<usize>("TLS_WARM_CONNECTIONS")
+        .optional();
+    #[cfg(not(feature = "tls"))]
+    let tls_warm_connections = bpaf::pure(None);
 
     let boot_strap_addr = short('b')
         .long("bootstrap")
-        .help("Bootstrap dns for resolving DoT/DoH upstreams")
+        .help("Bootstrap dns for resolving DoT/DoH upstreams. 1.1.1.1:53 is used when it's not specified")
         .argument::<String>("BOOT_STRAP")
-        .fallback_with::<_, String>(|| Ok("1.1.1.1:53".to_owned()))
-        .parse(|addr| addr.to_socket_addrs().map(Vec::from_iter));
+        .parse(|addr| addr.to_socket_addrs().map(Vec::from_iter))
+        .optional();
+
+    let timeout = bpaf::long("timeout")
+        .help("Deadline in milliseconds for answering a query. SERVFAIL is sent to client when upstream fails to respond in time. 3000 is used when it's not specified")
+        .argument::<u64>("TIMEOUT")
+        .optional();
 
     let log_level = short('L')
         .long("log-level")
         .help("Display level of logger: error,warn,info,debug,trace. number 1-5 can be used to represent level in the same order from error to trance")
         .argument::<Level>("LOG_LEVEL")
-        .fallback(Level::INFO);
+        .optional();
 
-    construct!(Config {
+    construct!(CliArgs {
+        config,
         listen_addr,
+        tls_listen_addr,
+        https_listen_addr,
+        http_listen_addr,
+        quic_listen_addr,
+        cert,
+        key,
         upstream_addr,
+        strategy,
+        hedge,
+        fallback,
+        upstream_timeout,
+        retries,
+        tls_connections,
+        tls_warm_connections,
         boot_strap_addr,
+        timeout,
         log_level,
         thread_count
     })
-    .to_options()
-    .run()
+}
+
+// resolve address with default port when it's absent from the input.
+fn resolve_addr(addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    match addr.to_socket_addrs() {
+        Ok(addrs) => Ok(Vec::from_iter(addrs)),
+        Err(_) => (addr, port).to_socket_addrs().map(Vec::from_iter),
+    }
+}
+
+#[cfg_attr(not(feature = "https"), allow(dead_code))]
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    Tcp(Vec<SocketAddr>),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => match addr.first() {
+                Some(addr) => write!(f, "{addr}"),
+                None => Ok(()),
+            },
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => resolve_addr(s, 80).map(Self::Tcp),
+        }
+    }
+}
+
+// upstreams queries are spread across by strategy.
+#[derive(Debug)]
+pub struct UpstreamGroup {
+    pub addr: Vec<Upstream>,
+    pub strategy: Strategy,
+    pub hedge: Option<Hedge>,
+    pub fallback: Fallback,
+    // deadline of each attempt of querying an upstream. query deadline is split between attempts
+    // when it's not specified.
+    pub timeout: Option<Duration>,
+    // attempts after the first one failed.
+    pub retries: u32,
+    #[cfg(feature = "tls")]
+    pub tls_pool: TlsPool,
+}
+
+// pipelined connections to each DoT upstream.
+#[cfg(feature = "tls")]
+#[derive(Clone, Copy, Debug)]
+pub struct TlsPool {
+    pub size: usize,
+    // connections kept open when there is no query.
+    pub warm: usize,
+}
+
+#[cfg(feature = "tls")]
+impl TlsPool {
+    fn new(size: Option<usize>, warm: Option<usize>) -> Result<Self, Error> {
+        let size = size.unwrap_or(1);
+        let warm = warm.unwrap_or(1);
+        if size == 0 {
+            return Err(Error::from("tls connections must be greater than 0"));
+        }
+        if warm > size {
+            return Err(Error::from(
+                "tls warm connections must not be greater than tls connections",
+            ));
+        }
+        Ok(Self { size, warm })
+    }
+}
+
+// name of default upstream group in routes.
+pub const DEFAULT_GROUP: &str = "default";
+
+// query with name matching suffix is forwarded to group.
+#[derive(Debug)]
+pub struct Route {
+    // lower case domain name without leading and trailing dot.
+    pub suffix: String,
+    pub group: String,
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub addr: UpstreamVariant,
+    pub weight: u32,
+    // deadline of each attempt and retries overriding the ones of group.
+    pub timeout: Option<Duration>,
+    pub retries: Option<u32>,
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        let mut sep = '@';
+        if self.weight != 1 {
+            write!(f, "{sep}{}", self.weight)?;
+            sep = ',';
+        }
+        if let Some(timeout) = self.timeout {
+            write!(f, "{sep}timeout={}", timeout.as_millis())?;
+            sep = ',';
+        }
+        if let Some(retries) = self.retries {
+            write!(f, "{sep}retries={retries}")?;
+        }
+        Ok(())
+    }
+}
+
+// upstream with optional suffix of comma separated options in the form of
+// 8.8.8.8:53@2,timeout=500,retries=1. bare number is weight and it's 1 when absent. timeout in
+// milliseconds and retries override the ones of group.
+impl FromStr for Upstream {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, opts) = match s.rsplit_once('@') {
+            Some((addr, opts))
+                if opts
+                    .split(',')
+                    .all(|opt| opt.contains('=') || opt.trim().parse::<u32>().is_ok()) =>
+            {
+                (addr, Some(opts))
+            }
+            _ => (s, None),
+        };
+
+        let mut this = Self {
+            addr: addr.parse()?,
+            weight: 1,
+            timeout: None,
+            retries: None,
+        };
+
+        for opt in opts.into_iter().flat_map(|opts| opts.split(',')) {
+            let (key, value) = opt.split_once('=').unwrap_or(("weight", opt));
+            let value = value.trim();
+            let invalid = || Error::from(format!("invalid upstream {} {value}", key.trim()));
+            match key.trim() {
+                "weight" => match value.parse() {
+                    Ok(0) => return Err(Error::from("upstream weight must be greater than 0")),
+                    Ok(weight) => this.weight = weight,
+                    Err(_) => return Err(invalid()),
+                },
+                "timeout" => {
+                    let timeout = value.parse().map_err(|_| invalid())?;
+                    this.timeout = Some(Duration::from_millis(timeout));
+                }
+                "retries" => this.retries = Some(value.parse().map_err(|_| invalid())?),
+                key => {
+                    return Err(Error::from(format!(
+                        "unknown upstream option {key}. expecting one of weight,timeout,retries"
+                    )))
+                }
+            }
+        }
+
+        Ok(this)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    // weighted random.
+    Random,
+    // lowest exponentially weighted moving average of response time.
+    Latency,
+    // query all upstreams in parallel and take the first answer.
+    Race,
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::RoundRobin => "round-robin",
+            Self::Random => "random",
+            Self::Latency => "latency",
+            Self::Race => "race",
+        })
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "round-robin" => Ok(Self::RoundRobin),
+            "random" => Ok(Self::Random),
+            "latency" => Ok(Self::Latency),
+            "race" => Ok(Self::Race),
+            s => Err(Error::from(format!(
+                "unknown strategy {s}. expecting one of round-robin,random,latency,race"
+            ))),
+        }
+    }
+}
+
+// delay before query is hedged to the next upstream.
+#[derive(Clone, Copy, Debug)]
+pub enum Hedge {
+    Delay(Duration),
+    // 95th percentile of response time learned from the upstream.
+    P95,
+}
+
+impl fmt::Display for Hedge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Delay(delay) => write!(f, "{}", delay.as_millis()),
+            Self::P95 => f.write_str("p95"),
+        }
+    }
+}
+
+impl FromStr for Hedge {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "p95" => Ok(Self::P95),
+            s => s
+                .parse()
+                .map(|ms| Self::Delay(Duration::from_millis(ms)))
+                .map_err(|_| {
+                    Error::from(format!(
+                        "invalid hedge {s}. expecting p95 or delay in milliseconds"
+                    ))
+                }),
+        }
+    }
+}
+
+// set of result codes of upstream response retried with the next upstream. bit n is set for
+// result code n.
+#[derive(Clone, Copy, Debug)]
+pub struct Fallback(u16);
+
+impl Fallback {
+    const CODES: [(ResultCode, &'static str); 3] = [
+        (ResultCode::SERVFAIL, "servfail"),
+        (ResultCode::NOTIMP, "notimp"),
+        (ResultCode::REFUSED, "refused"),
+    ];
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, code: u8) -> bool {
+        code < 16 && self.0 & (1 << code) != 0
+    }
+
+    const fn with(self, code: ResultCode) -> Self {
+        Self(self.0 | 1 << code as u8)
+    }
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Self(0).with(ResultCode::SERVFAIL).with(ResultCode::REFUSED)
+    }
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+        let mut codes = Self::CODES
+            .iter()
+            .filter(|(code, _)| self.contains(*code as u8));
+        if let Some((_, name)) = codes.next() {
+            f.write_str(name)?;
+        }
+        codes.try_for_each(|(_, name)| write!(f, ",{name}"))
+    }
+}
+
+impl FromStr for Fallback {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "none" {
+            return Ok(Self(0));
+        }
+        s.split(',').try_fold(Self(0), |fallback, s| {
+            let s = s.trim();
+            Self::CODES
+                .iter()
+                .find(|(_, name)| *name == s)
+                .map(|(code, _)| fallback.with(*code))
+                .ok_or_else(|| {
+                    Error::from(format!(
+                        "unknown result code {s}. expecting none or any of servfail,refused,notimp"
+                    ))
+                })
+        })
+    }
 }
 
 #[derive(Debug)]
 pub enum UpstreamVariant {
     Udp(SocketAddr),
+    Tcp(SocketAddr),
     #[cfg(feature = "tls")]
     Tls(String),
     #[cfg(feature = "https")]
     Https(String),
+    #[cfg(feature = "quic")]
+    Quic(String),
+}
+
+impl fmt::Display for UpstreamVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp(addr) => write!(f, "{addr}"),
+            Self::Tcp(addr) => write!(f, "tcp://{addr}"),
+            #[cfg(feature = "tls")]
+            Self::Tls(uri) => f.write_str(uri),
+            #[cfg(feature = "https")]
+            Self::Https(uri) => f.write_str(uri),
+            #[cfg(feature = "quic")]
+            Self::Quic(uri) => f.write_str(uri),
+        }
+    }
 }
 
 impl FromStr for UpstreamVariant {
@@ -85,6 +865,94 @@ impl FromStr for UpstreamVariant {
             return Ok(Self::Https(String::from(s)));
         }
 
+        #[cfg(feature = "quic")]
+        if s.starts_with("quic://") {
+            return Ok(Self::Quic(String::from(s)));
+        }
+
+        // port 53 is used when it's absent from tcp upstream.
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            return addr
+                .parse()
+                .or_else(|e| {
+                    addr.parse()
+                        .map(|ip| SocketAddr::new(ip, 53))
+                        .map_err(|_| e)
+                })
+                .map(Self::Tcp);
+        }
+
         s.parse().map(Self::Udp)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn upstream_options() {
+        let upstream = "8.8.8.8:53".parse::<Upstream>().unwrap();
+        assert_eq!(upstream.weight, 1);
+        assert_eq!(upstream.timeout, None);
+        assert_eq!(upstream.retries, None);
+        assert_eq!(upstream.to_string(), "8.8.8.8:53");
+
+        let upstream = "8.8.8.8:53@2".parse::<Upstream>().unwrap();
+        assert_eq!(upstream.weight, 2);
+        assert_eq!(upstream.to_string(), "8.8.8.8:53@2");
+
+        let upstream = "tcp://8.8.8.8:53@timeout=500, retries=1"
+            .parse::<Upstream>()
+            .unwrap();
+        assert_eq!(upstream.weight, 1);
+        assert_eq!(upstream.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(upstream.retries, Some(1));
+        assert_eq!(
+            upstream.to_string(),
+            "tcp://8.8.8.8:53@timeout=500,retries=1"
+        );
+
+        let upstream = "8.8.8.8:53@3,retries=2".parse::<Upstream>().unwrap();
+        assert_eq!(upstream.weight, 3);
+        assert_eq!(upstream.retries, Some(2));
+        assert_eq!(upstream.to_string(), "8.8.8.8:53@3,retries=2");
+
+        for s in [
+            "8.8.8.8:53@0",
+            "8.8.8.8:53@timeout=fast",
+            "8.8.8.8:53@retry=1",
+        ] {
+            assert!(s.parse::<Upstream>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn route_suffix_normalize() {
+        assert_eq!(route_suffix("Example.COM").as_deref(), Some("example.com"));
+        assert_eq!(
+            route_suffix("*.example.com").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            route_suffix(".example.com.").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(route_suffix("*.lan.").as_deref(), Some("lan"));
+    }
+
+    #[test]
+    fn route_suffix_root() {
+        assert_eq!(route_suffix("").as_deref(), Some(""));
+        assert_eq!(route_suffix(".").as_deref(), Some(""));
+        assert_eq!(route_suffix("*.").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn route_suffix_invalid() {
+        assert_eq!(route_suffix("example..com"), None);
+        assert_eq!(route_suffix("*.*.example.com"), None);
+        assert_eq!(route_suffix(&"a".repeat(64)), None);
+        assert_eq!(route_suffix(&["a"; 128].join(".")), None);
+    }
+}
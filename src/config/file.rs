@@ -0,0 +1,284 @@
+// TOML config file. every key is optional and mirrors command line argument of the same purpose.
+
+use core::{borrow::Borrow, fmt, net::SocketAddr, str::FromStr};
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{de, Deserialize, Deserializer};
+use toml::Spanned;
+use tracing::Level;
+
+use crate::error::Error;
+
+use super::{resolve_addr, route_suffix, Fallback, Hedge, ListenAddr, Strategy, DEFAULT_GROUP};
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct File {
+    pub(super) thread: Option<usize>,
+    pub(super) listen: Listen,
+    pub(super) upstream: Upstream,
+    // named upstream groups in [group.<name>] tables.
+    pub(super) group: BTreeMap<GroupName, Group>,
+    // group name mapped to the domain suffixes routed to it. span of name is kept for reporting
+    // route to unknown group.
+    pub(super) route: BTreeMap<Spanned<String>, Vec<Suffix>>,
+    pub(super) policy: Policy,
+    pub(super) log: Log,
+}
+
+// feature gated keys are treated as unknown when the feature is not enabled.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Listen {
+    #[serde(deserialize_with = "addr::<_, 53>")]
+    pub(super) addr: Option<Vec<SocketAddr>>,
+    #[cfg_attr(not(feature = "tls"), serde(skip))]
+    #[serde(deserialize_with = "addr::<_, 853>")]
+    pub(super) tls: Option<Vec<SocketAddr>>,
+    #[cfg_attr(not(feature = "https"), serde(skip))]
+    #[serde(deserialize_with = "addr::<_, 443>")]
+    pub(super) https: Option<Vec<SocketAddr>>,
+    #[cfg_attr(not(feature = "https"), serde(skip))]
+    #[serde(deserialize_with = "parse")]
+    pub(super) http: Option<ListenAddr>,
+    #[cfg_attr(not(feature = "quic"), serde(skip))]
+    #[serde(deserialize_with = "addr::<_, 853>")]
+    pub(super) quic: Option<Vec<SocketAddr>>,
+    #[cfg_attr(
+        not(any(feature = "tls", feature = "https", feature = "quic")),
+        serde(skip)
+    )]
+    pub(super) cert: Option<PathBuf>,
+    #[cfg_attr(
+        not(any(feature = "tls", feature = "https", feature = "quic")),
+        serde(skip)
+    )]
+    pub(super) key: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Upstream {
+    #[serde(deserialize_with = "parse_many")]
+    pub(super) addr: Option<Vec<super::Upstream>>,
+    #[serde(deserialize_with = "parse")]
+    pub(super) strategy: Option<Strategy>,
+    #[serde(deserialize_with = "parse")]
+    pub(super) hedge: Option<Hedge>,
+    #[serde(deserialize_with = "parse")]
+    pub(super) fallback: Option<Fallback>,
+    // deadline of each attempt of querying an upstream in milliseconds.
+    pub(super) timeout: Option<u64>,
+    pub(super) retries: Option<u32>,
+    #[cfg_attr(not(feature = "tls"), serde(skip))]
+    #[serde(deserialize_with = "connections")]
+    pub(super) tls_connections: Option<usize>,
+    // span is kept for reporting more warm connections than connections.
+    #[cfg_attr(not(feature = "tls"), serde(skip))]
+    pub(super) tls_warm_connections: Option<Spanned<usize>>,
+    #[serde(deserialize_with = "addr::<_, 53>")]
+    pub(super) bootstrap: Option<Vec<SocketAddr>>,
+}
+
+// addr is the only required key of group.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct Group {
+    #[serde(deserialize_with = "upstreams")]
+    pub(super) addr: Vec<super::Upstream>,
+    #[serde(default, deserialize_with = "parse")]
+    pub(super) strategy: Option<Strategy>,
+    #[serde(default, deserialize_with = "parse")]
+    pub(super) hedge: Option<Hedge>,
+    #[serde(default, deserialize_with = "parse")]
+    pub(super) fallback: Option<Fallback>,
+    // deadline of each attempt of querying an upstream in milliseconds.
+    #[serde(default)]
+    pub(super) timeout: Option<u64>,
+    #[serde(default)]
+    pub(super) retries: Option<u32>,
+    #[cfg_attr(not(feature = "tls"), serde(skip), allow(dead_code))]
+    #[serde(default, deserialize_with = "connections")]
+    pub(super) tls_connections: Option<usize>,
+    #[cfg_attr(not(feature = "tls"), serde(skip), allow(dead_code))]
+    #[serde(default)]
+    pub(super) tls_warm_connections: Option<Spanned<usize>>,
+}
+
+// name of [group.<name>] table. default is reserved for [upstream] table.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct GroupName(pub(super) String);
+
+impl Borrow<str> for GroupName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'de> Deserialize<'de> for GroupName {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(de)?;
+        if name == DEFAULT_GROUP {
+            return Err(de::Error::custom(format!(
+                "group name {DEFAULT_GROUP} is reserved for upstream table"
+            )));
+        }
+        Ok(Self(name))
+    }
+}
+
+// domain suffix of route in normalized form.
+pub(super) struct Suffix(pub(super) String);
+
+impl<'de> Deserialize<'de> for Suffix {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(de)?;
+        route_suffix(&s)
+            .map(Self)
+            .ok_or_else(|| de::Error::custom(format!("invalid domain suffix {s:?}")))
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Policy {
+    // deadline of answering a query in milliseconds.
+    pub(super) timeout: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Log {
+    #[serde(deserialize_with = "parse")]
+    pub(super) level: Option<Level>,
+}
+
+impl File {
+    pub(super) fn read(path: &Path) -> Result<Self, Error> {
+        let display = path.display();
+        let file = fs::read_to_string(path).map_err(|e| format!("{display}: {e}"))?;
+        // toml error carries line and column of offending value.
+        let this = toml::from_str::<Self>(&file).map_err(|e| format!("{display}: {e}"))?;
+
+        // group of route can only be checked after the whole file is read.
+        for name in this.route.keys() {
+            let group = name.get_ref().as_str();
+            if group != DEFAULT_GROUP && !this.group.contains_key(group) {
+                let (line, column) = line_column(&file, name.span().start);
+                return Err(Error::from(format!(
+                    "{display}: route error at line {line}, column {column}: unknown group {group}"
+                )));
+            }
+        }
+
+        // warm connections can only be checked against connections of the same table.
+        #[cfg(feature = "tls")]
+        {
+            let upstream = &this.upstream;
+            let pools =
+                core::iter::once((upstream.tls_connections, &upstream.tls_warm_connections)).chain(
+                    this.group
+                        .values()
+                        .map(|group| (group.tls_connections, &group.tls_warm_connections)),
+                );
+            for (size, warm) in pools {
+                let Some(warm) = warm else {
+                    continue;
+                };
+                if let Err(e) = super::TlsPool::new(size, Some(*warm.get_ref())) {
+                    let (line, column) = line_column(&file, warm.span().start);
+                    return Err(Error::from(format!(
+                        "{display}: tls_warm_connections error at line {line}, column {column}: {e}"
+                    )));
+                }
+            }
+        }
+
+        Ok(this)
+    }
+}
+
+// one based line and column of byte offset in file.
+fn line_column(file: &str, offset: usize) -> (usize, usize) {
+    let head = &file[..offset];
+    let line = head.matches('\n').count() + 1;
+    let column = head.rfind('\n').map_or(offset, |pos| offset - pos - 1) + 1;
+    (line, column)
+}
+
+// parse value through it's FromStr implementation so error can be attached to the offending line.
+fn parse<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(de)?;
+    s.parse().map(Some).map_err(de::Error::custom)
+}
+
+fn parse_many<'de, D, T>(de: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    Vec::<String>::deserialize(de)?
+        .iter()
+        .map(|s| {
+            s.parse()
+                .map_err(|e| de::Error::custom(format!("{s}: {e}")))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+// upstreams of group. empty list is rejected.
+fn upstreams<'de, D>(de: D) -> Result<Vec<super::Upstream>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let addr = parse_many(de)?.unwrap_or_default();
+    if addr.is_empty() {
+        return Err(de::Error::custom(
+            "at least one upstream dns server is needed",
+        ));
+    }
+    Ok(addr)
+}
+
+// connections of DoT upstream. 0 is rejected.
+#[cfg(feature = "tls")]
+fn connections<'de, D>(de: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match usize::deserialize(de)? {
+        0 => Err(de::Error::custom("tls connections must be greater than 0")),
+        size => Ok(Some(size)),
+    }
+}
+
+// resolve addresses with default port when it's absent from the value.
+fn addr<'de, D, const PORT: u16>(de: D) -> Result<Option<Vec<SocketAddr>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut addrs = Vec::new();
+    for s in Vec::<String>::deserialize(de)? {
+        let addr = resolve_addr(&s, PORT).map_err(|e| de::Error::custom(format!("{s}: {e}")))?;
+        addrs.extend(addr);
+    }
+    Ok(Some(addrs))
+}
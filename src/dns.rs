@@ -11,6 +11,9 @@ use std::io;
 
 use tracing::warn;
 
+// udp payload size advertised in OPT record of response. RFC 6891 6.2.5
+pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+
 pub struct Buf<'a> {
     pub buf: &'a mut [u8],
     pub pos: usize,
@@ -21,7 +24,7 @@ impl<'a> Buf<'a> {
         Buf { buf, pos: 0 }
     }
 
-    #[cfg(any(feature = "https", feature = "tls"))]
+    #[cfg(any(feature = "https", feature = "tls", feature = "quic"))]
     pub fn as_slice(&self) -> &[u8] {
         &self.buf[..self.pos]
     }
@@ -35,7 +38,7 @@ impl<'a> Buf<'a> {
     }
 
     fn read(&mut self) -> io::Result<u8> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(eof_err());
         }
 
@@ -46,14 +49,14 @@ impl<'a> Buf<'a> {
     }
 
     fn get(&mut self, pos: usize) -> io::Result<u8> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err(eof_err());
         }
         Ok(self.buf[pos])
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> io::Result<&[u8]> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             return Err(eof_err());
         }
         Ok(&self.buf[start..start + len])
@@ -125,7 +128,7 @@ impl<'a> Buf<'a> {
     }
 
     fn write(&mut self, val: u8) -> io::Result<()> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(eof_err());
         }
         self.buf[self.pos] = val;
@@ -150,7 +153,8 @@ impl<'a> Buf<'a> {
     }
 
     fn write_qname(&mut self, qname: &str) -> io::Result<()> {
-        for label in qname.split('.') {
+        // root domain is an empty string and only has the terminating zero length label.
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
             let len = label.len();
 
             label_len_check(len)?;
@@ -311,6 +315,7 @@ pub enum Query {
     CNAME, // 5
     MX,    // 15
     AAAA,  // 28
+    OPT,   // 41
 }
 
 impl From<Query> for u16 {
@@ -322,6 +327,7 @@ impl From<Query> for u16 {
             Query::CNAME => 5,
             Query::MX => 15,
             Query::AAAA => 28,
+            Query::OPT => 41,
         }
     }
 }
@@ -334,6 +340,7 @@ impl From<u16> for Query {
             5 => Query::CNAME,
             15 => Query::MX,
             28 => Query::AAAA,
+            41 => Query::OPT,
             v => Query::UNKNOWN(v),
         }
     }
@@ -382,9 +389,19 @@ pub enum Record {
     CNAME { host: String },                // 5
     MX { priority: u16, host: String },    // 15
     AAAA { addr: Ipv6Addr },               // 28
+    OPT { udp_payload_size: u16 },         // 41
 }
 
 impl Answer {
+    // OPT pseudo record without options. RFC 6891 6.1.2
+    pub(super) const fn opt(udp_payload_size: u16) -> Self {
+        Self {
+            domain: String::new(),
+            ttl: 0,
+            record: Record::OPT { udp_payload_size },
+        }
+    }
+
     pub(super) const fn ttl(&self) -> u32 {
         self.ttl
     }
@@ -400,7 +417,7 @@ impl Answer {
 
         let qtype_num = buf.read_u16()?;
         let qtype = Query::from(qtype_num);
-        let _ = buf.read_u16()?;
+        let class = buf.read_u16()?;
         let ttl = buf.read_u32()?;
         let data_len = buf.read_u16()?;
 
@@ -448,6 +465,13 @@ impl Answer {
                 buf.read_qname(&mut host)?;
                 Record::MX { priority, host }
             }
+            // class field of OPT is the requester's udp payload size. options are skipped.
+            Query::OPT => {
+                buf.step(data_len as usize);
+                Record::OPT {
+                    udp_payload_size: class,
+                }
+            }
             Query::UNKNOWN(_) => {
                 buf.step(data_len as usize);
                 Record::UNKNOWN {
@@ -537,6 +561,13 @@ impl Answer {
                     buf.write_u16(*octet)?;
                 }
             }
+            Record::OPT { udp_payload_size } => {
+                buf.write_qname(domain)?;
+                buf.write_u16(Query::OPT.into())?;
+                buf.write_u16(udp_payload_size)?;
+                buf.write_u32(ttl)?;
+                buf.write_u16(0)?;
+            }
             ref record => warn!("skipping record: {record:?}"),
         }
 
@@ -590,7 +621,7 @@ impl Packet {
     }
 }
 
-impl<'a> Packet<&'a [Answer]> {
+impl Packet<&[Answer]> {
     pub const fn new_ref() -> Self {
         Self {
             header: Header::new(),
@@ -627,6 +658,31 @@ impl<A> Packet<A>
 where
     A: Deref<Target = [Answer]>,
 {
+    // udp payload size advertised by OPT record. value smaller than 512 is treated as 512.
+    // RFC 6891 6.2.5
+    pub(super) fn udp_payload_size(&self) -> Option<u16> {
+        self.resources.iter().find_map(|rec| match rec.record {
+            Record::OPT { udp_payload_size } => Some(udp_payload_size.max(512)),
+            _ => None,
+        })
+    }
+
+    // turn parsed query into response. OPT record of query is replaced with our own.
+    // RFC 6891 7
+    pub(super) fn set_response(&mut self) {
+        self.header.response = true;
+        self.header.recursion_available = true;
+        self.header.truncated_message = false;
+        self.header.authoritative_answer = false;
+        self.authorities.clear();
+
+        let edns = self.udp_payload_size().is_some();
+        self.resources.clear();
+        if edns {
+            self.resources.push(Answer::opt(UDP_PAYLOAD_SIZE));
+        }
+    }
+
     pub(super) fn write(&mut self, buf: &mut Buf) -> io::Result<()> {
         let answers = self.answers.deref();
 
@@ -654,11 +710,173 @@ where
     }
 }
 
+// dns message too short for header or with response flag set is not a query and must be
+// dropped. replying to it could cause endless message loop between servers.
+pub fn is_query(buf: &[u8]) -> bool {
+    buf.len() >= 12 && buf[2] & 0x80 == 0
+}
+
+// response with TC flag set doesn't carry complete answer and query should be retried over tcp.
+// RFC 1035 4.2.1
+pub fn is_truncated(buf: &[u8]) -> bool {
+    buf.len() >= 12 && buf[2] & 0x02 != 0
+}
+
+// check if query can be proxied. error is the result code of response to reject it.
+pub fn check_query(query: &mut [u8]) -> Result<(), ResultCode> {
+    let mut header = Header::new();
+    header
+        .read(&mut Buf::new(query))
+        .map_err(|_| ResultCode::FORMERR)?;
+
+    // only standard query is supported. RFC 1035 4.1.1
+    if header.opcode != 0 {
+        return Err(ResultCode::NOTIMP);
+    }
+
+    let mut packet = Packet::new_ref();
+    match packet.read(&mut Buf::new(query)) {
+        Ok(_) if packet.questions.len() == 1 => Ok(()),
+        _ => Err(ResultCode::FORMERR),
+    }
+}
+
+// error response to query with ID, opcode, RD flag and question echoed.
+pub fn error_response(query: &mut [u8], rescode: ResultCode) -> Vec<u8> {
+    let mut packet = Packet::new_ref();
+    if packet.read(&mut Buf::new(query)).is_err() {
+        // question and records of malformed query can't be echoed.
+        packet.questions.clear();
+        packet.resources.clear();
+    }
+
+    packet.set_response();
+    packet.header.rescode = rescode;
+
+    let mut buf = vec![0; 512];
+    let dns_buf = &mut Buf::new(&mut buf);
+
+    if packet.write(dns_buf).is_err() {
+        packet.questions.clear();
+        packet.resources.clear();
+        dns_buf.pos = 0;
+        // header alone always fits.
+        let _ = packet.write(dns_buf);
+    }
+
+    let len = dns_buf.pos;
+    buf.truncate(len);
+    buf
+}
+
+// query of root NS record for probing health of upstream.
+pub fn probe_query(id: u16) -> Vec<u8> {
+    let mut packet = Packet::new_ref();
+    packet.header.id = id;
+    packet.header.recursion_desired = true;
+    packet
+        .questions
+        .push(Question::new(String::new(), Query::NS));
+
+    let mut buf = vec![0; 512];
+    let dns_buf = &mut Buf::new(&mut buf);
+
+    // single question of root always fits.
+    let _ = packet.write(dns_buf);
+
+    let len = dns_buf.pos;
+    buf.truncate(len);
+    buf
+}
+
+// raw bytes of the first question of message. name of question is a sequence of labels ended by
+// root label or compression pointer. RFC 1035 4.1.4
+pub fn question(buf: &[u8]) -> Option<&[u8]> {
+    if buf.get(4..6)? == [0, 0] {
+        return None;
+    }
+    let mut pos = 12;
+    loop {
+        let len = *buf.get(pos)? as usize;
+        match len {
+            0 => {
+                pos += 1;
+                break;
+            }
+            len if len & 0xC0 == 0xC0 => {
+                pos += 2;
+                break;
+            }
+            len => pos += len + 1,
+        }
+    }
+    // type and class.
+    buf.get(12..pos + 4)
+}
+
+// udp payload size advertised by OPT record of query. None is returned when requester has no
+// EDNS support and is limited to 512 bytes.
+pub fn udp_payload_size(query: &mut [u8]) -> Option<u16> {
+    let mut packet = Packet::new_ref();
+    packet.read(&mut Buf::new(query)).ok()?;
+    packet.udp_payload_size()
+}
+
+// truncate response exceeding limit to header and question section with TC flag set so
+// requester can retry over tcp. RFC 2181 9
+// OPT record is kept when requester supports EDNS. RFC 6891 7
+pub fn truncate(buf: &mut Vec<u8>, limit: usize, edns: bool) {
+    if buf.len() <= limit {
+        return;
+    }
+
+    // root domain, type, class, ttl and empty rdata.
+    const OPT_LEN: usize = 11;
+
+    let opt_len = if edns { OPT_LEN } else { 0 };
+
+    let mut header = Header::new();
+    let mut dns_buf = Buf::new(buf);
+
+    let len = header.read(&mut dns_buf).and_then(|_| {
+        for _ in 0..header.questions {
+            let mut question = Question::NEW;
+            question.read(&mut dns_buf)?;
+        }
+        Ok(dns_buf.pos)
+    });
+
+    let len = match len {
+        Ok(len) if len + opt_len <= limit => len,
+        _ => {
+            header.questions = 0;
+            12
+        }
+    };
+
+    header.truncated_message = true;
+    header.answers = 0;
+    header.authoritative_entries = 0;
+    header.resource_entries = edns as u16;
+
+    // header is rewritten in place and can not overflow.
+    let _ = header.write(&mut Buf::new(buf));
+    buf.truncate(len);
+
+    if edns {
+        buf.resize(len + OPT_LEN, 0);
+        let mut dns_buf = Buf::new(buf);
+        dns_buf.seek(len);
+        // buffer is sized for the record and can not overflow.
+        let _ = Answer::opt(UDP_PAYLOAD_SIZE).write(&mut dns_buf);
+    }
+}
+
 #[cold]
 #[inline(never)]
 fn eof_err() -> io::Error {
     io::Error::new(
         io::ErrorKind::UnexpectedEof,
-        "buffer overflow. Buf is limited to 512 bytes",
+        "buffer overflow. dns message exceeds the size of Buf",
     )
 }
@@ -1,3 +1,17 @@
+use core::{fmt, time::Duration};
+
 use std::error;
 
 pub type Error = Box<dyn error::Error + Send + Sync + 'static>;
+
+// dns lookup not finished before deadline. it's answered with SERVFAIL.
+#[derive(Debug)]
+pub struct TimeoutError(pub Duration);
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out after {}ms", self.0.as_millis())
+    }
+}
+
+impl error::Error for TimeoutError {}
@@ -12,23 +12,33 @@ use tracing::{error, info};
 
 use self::{
     app::App,
-    config::{parse_arg, Config},
+    config::{parse_arg, Command, Config},
     error::Error,
+    util::LogHandle,
 };
 
 fn main() {
-    let cfg = parse_arg();
-
-    tracing_subscriber::fmt()
-        .with_env_filter(format!("dns_proxy={}", cfg.log_level.as_str()))
-        .init();
-
-    if let Err(e) = run(cfg) {
+    let cfg = match parse_arg() {
+        Ok(Command::Run(cfg)) => cfg,
+        Ok(Command::Check(cfg)) => check(cfg),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(util::log_filter(cfg.log_level))
+        .with_filter_reloading();
+    let log = builder.reload_handle();
+    builder.init();
+
+    if let Err(e) = run(cfg, log) {
         error!("fatal error: {}", e);
     }
 }
 
-fn run(cfg: Config) -> Result<(), Error> {
+fn run(cfg: Config, log: LogHandle) -> Result<(), Error> {
     info!("starting dns-proxy with configuration: {:?}", cfg);
 
     let mut rt = tokio::runtime::Builder::new_multi_thread();
@@ -37,5 +47,17 @@ fn run(cfg: Config) -> Result<(), Error> {
         rt.worker_threads(count);
     }
 
-    rt.enable_all().build()?.block_on(App::run(cfg))
+    rt.enable_all().build()?.block_on(App::run(cfg, log))
+}
+
+// print normalized configuration and exit with non zero code when it's invalid.
+fn check(cfg: Config) -> ! {
+    print!("{cfg}");
+    match App::check(&cfg) {
+        Ok(_) => std::process::exit(0),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
 }
@@ -1,8 +1,15 @@
 #[cfg(feature = "https")]
 pub mod https;
+#[cfg(feature = "quic")]
+pub mod quic;
 #[cfg(feature = "tls")]
 pub mod tls;
 
+pub mod group;
+mod inflight;
+mod pipeline;
+pub mod route;
+pub mod tcp;
 pub mod udp;
 
 use core::future::Future;
@@ -13,19 +20,58 @@ use crate::{error::Error, util::BoxFuture};
 /// raw dns response bytes.
 pub trait Proxy: Send + Sync {
     fn proxy(&self, buf: Box<[u8]>) -> impl Future<Output = Result<Vec<u8>, Error>> + Send;
+
+    /// close connections to upstream. called on shutdown when no more query would be proxied.
+    fn close(self) -> impl Future<Output = ()> + Send
+    where
+        Self: Sized,
+    {
+        async {}
+    }
 }
 
 // helper trait making Proxy trait object safe.
 pub(crate) trait ProxyDyn: Send + Sync {
     fn proxy_dyn(&self, buf: Box<[u8]>) -> BoxFuture<'_, Result<Vec<u8>, Error>>;
+
+    fn close_dyn(self: Box<Self>) -> BoxFuture<'static, ()>;
 }
 
 impl<P> ProxyDyn for P
 where
-    P: Proxy,
+    P: Proxy + 'static,
 {
     #[inline]
     fn proxy_dyn(&self, buf: Box<[u8]>) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
         Box::pin(self.proxy(buf))
     }
+
+    #[inline]
+    fn close_dyn(self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin((*self).close())
+    }
+}
+
+// parse upstream uri and make sure it has a host to connect to.
+#[cfg(any(feature = "tls", feature = "quic"))]
+fn parse_uri(uri: &str) -> Result<http::Uri, Error> {
+    let uri = http::Uri::try_from(uri)?;
+    match uri.host() {
+        Some(_) => Ok(uri),
+        None => Err(Error::from(InvalidUri(uri))),
+    }
 }
+
+#[cfg(any(feature = "tls", feature = "quic"))]
+#[derive(Debug)]
+struct InvalidUri(http::Uri);
+
+#[cfg(any(feature = "tls", feature = "quic"))]
+impl core::fmt::Display for InvalidUri {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} is not valid uri.", self.0)
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "quic"))]
+impl std::error::Error for InvalidUri {}
@@ -0,0 +1,680 @@
+use core::{
+    future::{poll_fn, Future},
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    task::Poll,
+    time::Duration,
+};
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use tokio::time::{interval, sleep, timeout, MissedTickBehavior};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{Fallback, Hedge, Strategy},
+    dns::{self, Buf, Header, ResultCode},
+    error::{Error, TimeoutError},
+};
+
+use super::{Proxy, ProxyDyn};
+
+// response time counted for failed lookup. it keeps failing upstream from being the fastest one.
+const FAIL_LATENCY: Duration = Duration::from_secs(1);
+
+// one in every this many queries goes to random upstream with latency strategy so estimate of
+// slower upstreams is refreshed.
+const EXPLORE_RATIO: u32 = 32;
+
+// consecutive failures and timeouts before upstream is marked down.
+const FAIL_THRESHOLD: u32 = 3;
+
+// interval and deadline of health probe to upstream marked down.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// hedging delay for learned p95 response time when upstream doesn't have enough samples yet.
+const HEDGE_DELAY: Duration = Duration::from_millis(100);
+
+// response time samples kept for learning p95 of upstream.
+const SAMPLES: usize = 128;
+const MIN_SAMPLES: usize = 16;
+
+// lower bound of attempt deadline split from query deadline.
+const MIN_ATTEMPT_TIMEOUT: Duration = Duration::from_millis(1);
+
+// interval of logging upstream metrics.
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+// composite proxy spreading queries across upstreams of a group. upstreams marked down are
+// skipped until health probe finds them recovered.
+pub struct GroupProxy {
+    upstreams: Box<[Arc<Upstream>]>,
+    strategy: Strategy,
+    hedge: Option<Hedge>,
+    fallback: Fallback,
+    // deadline of answering a query. it's split between attempts of upstream without timeout.
+    deadline: Duration,
+    next: AtomicUsize,
+    // cancelled when proxy is closed or dropped. stops health probes and metrics report.
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+}
+
+pub struct Upstream {
+    name: String,
+    proxy: Box<dyn ProxyDyn>,
+    weight: u32,
+    // deadline of each attempt and attempts after the first one failed. deadline of query is
+    // split between attempts when timeout is not specified.
+    timeout: Option<Duration>,
+    retries: u32,
+    // exponentially weighted moving average of response time in microseconds. 0 for upstream
+    // not queried yet.
+    latency: AtomicU64,
+    // response time of recent successful queries in microseconds.
+    samples: Mutex<Samples>,
+    failures: AtomicU32,
+    down: AtomicBool,
+    metrics: Metrics,
+}
+
+#[derive(Default)]
+struct Metrics {
+    queries: AtomicU64,
+    failures: AtomicU64,
+    // times upstream answered first in raced or hedged query.
+    wins: AtomicU64,
+    // queries sent to upstream as hedge of slow one.
+    hedges: AtomicU64,
+    // responses from upstream retried with the next upstream for their result code.
+    fallbacks: AtomicU64,
+}
+
+// ring buffer of response time samples.
+struct Samples {
+    buf: [u32; SAMPLES],
+    len: usize,
+    pos: usize,
+}
+
+impl Samples {
+    const fn new() -> Self {
+        Self {
+            buf: [0; SAMPLES],
+            len: 0,
+            pos: 0,
+        }
+    }
+
+    fn push(&mut self, latency: Duration) {
+        self.buf[self.pos] = latency.as_micros().min(u32::MAX as u128) as u32;
+        self.pos = (self.pos + 1) % SAMPLES;
+        self.len = (self.len + 1).min(SAMPLES);
+    }
+
+    fn p95(&self) -> Option<Duration> {
+        if self.len < MIN_SAMPLES {
+            return None;
+        }
+        let mut buf = self.buf;
+        let buf = &mut buf[..self.len];
+        let idx = (buf.len() * 95).div_ceil(100) - 1;
+        let (_, p95, _) = buf.select_nth_unstable(idx);
+        Some(Duration::from_micros(*p95 as u64))
+    }
+}
+
+impl Upstream {
+    pub fn new(
+        name: String,
+        proxy: Box<dyn ProxyDyn>,
+        weight: u32,
+        timeout: Option<Duration>,
+        retries: u32,
+    ) -> Self {
+        Self {
+            name,
+            proxy,
+            weight,
+            timeout,
+            retries,
+            latency: AtomicU64::new(0),
+            samples: Mutex::new(Samples::new()),
+            failures: AtomicU32::new(0),
+            down: AtomicBool::new(false),
+            metrics: Metrics::default(),
+        }
+    }
+
+    // smoothing factor 1/8. RFC 6298 2.3
+    fn observe(&self, latency: Duration) {
+        let sample = latency.as_micros() as u64;
+        let _ = self
+            .latency
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| match avg {
+                0 => Some(sample.max(1)),
+                avg => Some((avg - avg / 8 + sample / 8).max(1)),
+            });
+    }
+
+    // every attempt has it's own deadline. dropping timed out attempt releases the resource of
+    // query held by proxy.
+    async fn query(&self, mut buf: Box<[u8]>, deadline: Duration) -> Result<Vec<u8>, Error> {
+        let mut retries = self.retries;
+        loop {
+            let query = match retries {
+                0 => mem::take(&mut buf),
+                _ => buf.clone(),
+            };
+            let res = match timeout(deadline, self.proxy.proxy_dyn(query)).await {
+                Ok(res) => res,
+                Err(_) => Err(Error::from(TimeoutError(deadline))),
+            };
+            match res {
+                Err(e) if retries > 0 => {
+                    debug!("upstream {} query error: {e}. retrying", self.name);
+                    retries -= 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn is_down(&self) -> bool {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    fn recover(&self) {
+        if self.failures.load(Ordering::Relaxed) != 0 {
+            self.failures.store(0, Ordering::Relaxed);
+        }
+        if self.is_down() && self.down.swap(false, Ordering::Relaxed) {
+            info!("upstream {} is up", self.name);
+        }
+    }
+}
+
+impl GroupProxy {
+    pub fn new(
+        upstreams: Vec<Upstream>,
+        strategy: Strategy,
+        hedge: Option<Hedge>,
+        fallback: Fallback,
+        deadline: Duration,
+    ) -> Self {
+        assert!(!upstreams.is_empty(), "upstream group must not be empty");
+
+        let upstreams = upstreams.into_iter().map(Arc::new).collect::<Box<[_]>>();
+        let shutdown = CancellationToken::new();
+        let tasks = TaskTracker::new();
+
+        let report = report_metrics(upstreams.clone());
+        let token = shutdown.clone();
+        tasks.spawn(async move {
+            token.run_until_cancelled(report).await;
+        });
+
+        Self {
+            upstreams,
+            strategy,
+            hedge,
+            fallback,
+            deadline,
+            next: AtomicUsize::new(0),
+            shutdown,
+            tasks,
+        }
+    }
+
+    // upstreams not marked down. every upstream is available when all of them are down.
+    fn available(&self) -> impl Iterator<Item = &Arc<Upstream>> + Clone {
+        let all_down = self.upstreams.iter().all(|u| u.is_down());
+        self.upstreams
+            .iter()
+            .filter(move |u| all_down || !u.is_down())
+    }
+
+    fn select(&self) -> &Arc<Upstream> {
+        let mut available = self.available();
+
+        match self.strategy {
+            // race strategy queries every available upstream and never selects one.
+            Strategy::RoundRobin | Strategy::Race => {
+                let n = self.next.fetch_add(1, Ordering::Relaxed) % available.clone().count();
+                available.nth(n).unwrap()
+            }
+            Strategy::Random => {
+                // summed in u64 so large weights of many upstreams can not overflow.
+                let weight = available.clone().map(|u| u.weight as u64).sum::<u64>();
+                let mut n = fastrand::u64(..weight);
+                available
+                    .find(|u| match n.checked_sub(u.weight as u64) {
+                        Some(rem) => {
+                            n = rem;
+                            false
+                        }
+                        None => true,
+                    })
+                    .unwrap()
+            }
+            Strategy::Latency => {
+                if fastrand::u32(..EXPLORE_RATIO) == 0 {
+                    let n = fastrand::usize(..available.clone().count());
+                    available.nth(n).unwrap()
+                } else {
+                    available
+                        .min_by_key(|u| u.latency.load(Ordering::Relaxed))
+                        .unwrap()
+                }
+            }
+        }
+    }
+
+    // the next available upstream after given one.
+    fn next_of(&self, upstream: &Arc<Upstream>) -> Option<&Arc<Upstream>> {
+        let idx = self
+            .upstreams
+            .iter()
+            .position(|u| Arc::ptr_eq(u, upstream))?;
+        let len = self.upstreams.len();
+        (1..len)
+            .map(|i| &self.upstreams[(idx + i) % len])
+            .find(|u| !u.is_down())
+    }
+
+    // response time and result are only recorded when upstream answers, fails or it's own
+    // attempt times out. query cancelled by deadline of client or losing race is not a failure of
+    // upstream.
+    async fn query(&self, upstream: &Arc<Upstream>, buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
+        upstream.metrics.queries.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+        let res = upstream.query(buf, self.attempt_timeout(upstream)).await;
+        self.record(upstream, start.elapsed(), res.is_err());
+        res
+    }
+
+    // deadline of each attempt of querying upstream. when it's not specified deadline of query is
+    // split evenly between attempts of upstreams queried one after another so timed out query can
+    // still fall back to the next upstream.
+    fn attempt_timeout(&self, upstream: &Upstream) -> Duration {
+        if let Some(timeout) = upstream.timeout {
+            return timeout;
+        }
+        let upstreams = match (self.strategy, self.hedge) {
+            // upstreams are queried in parallel.
+            (Strategy::Race, _) | (_, Some(_)) => 1,
+            _ if self.fallback.is_empty() || self.upstreams.len() < 2 => 1,
+            _ => 2,
+        };
+        let attempts = upstream.retries.saturating_add(1).saturating_mul(upstreams);
+        (self.deadline / attempts).max(MIN_ATTEMPT_TIMEOUT)
+    }
+
+    // track response time and consecutive failures of upstream and start probing it when it's
+    // marked down.
+    fn record(&self, upstream: &Arc<Upstream>, latency: Duration, failed: bool) {
+        if !failed {
+            upstream.samples.lock().unwrap().push(latency);
+            upstream.observe(latency);
+            upstream.recover();
+            return;
+        }
+
+        upstream.metrics.failures.fetch_add(1, Ordering::Relaxed);
+        upstream.observe(latency.max(FAIL_LATENCY));
+
+        let failures = upstream.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= FAIL_THRESHOLD && !upstream.down.swap(true, Ordering::Relaxed) {
+            warn!(
+                "upstream {} is down after {failures} consecutive failures",
+                upstream.name
+            );
+            let probe = health_probe(upstream.clone());
+            let token = self.shutdown.clone();
+            self.tasks.spawn(async move {
+                token.run_until_cancelled(probe).await;
+            });
+        }
+    }
+
+    // query selected upstream and retry with the next upstream when it fails or result code of
+    // response is in fallback set. result of the first upstream is returned when the retry fails.
+    async fn fallback(&self, buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
+        let primary = self.select();
+
+        if self.fallback.is_empty() {
+            return self.query(primary, buf).await;
+        }
+
+        let res = self.query(primary, buf.clone()).await;
+
+        let reason = match res {
+            Ok(ref buf) => {
+                let code = rescode(buf);
+                if !self.fallback.contains(code) {
+                    return res;
+                }
+                format!("responded {:?}", ResultCode::from(code))
+            }
+            Err(ref e) => format!("failed: {e}"),
+        };
+        let Some(secondary) = self.next_of(primary) else {
+            return res;
+        };
+
+        primary.metrics.fallbacks.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "upstream {} {reason}. retrying with upstream {}",
+            primary.name, secondary.name
+        );
+
+        match self.query(secondary, buf).await {
+            Ok(res) => Ok(res),
+            Err(e) => {
+                debug!("upstream {} fallback error: {e}", secondary.name);
+                res
+            }
+        }
+    }
+
+    // query every available upstream at once. the first NOERROR or NXDOMAIN response is returned
+    // and the rest of queries are cancelled. the last response or error is returned when none of
+    // the responses is an answer.
+    async fn race(&self, buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
+        let queries = self
+            .available()
+            .map(|upstream| self.race_query(upstream, buf.clone()))
+            .collect();
+        first_answer(queries).await
+    }
+
+    // query selected upstream and send the same query to the next upstream when there is no
+    // answer in hedging delay. the first answer from either of them is returned.
+    async fn hedge(&self, buf: Box<[u8]>, hedge: Hedge) -> Result<Vec<u8>, Error> {
+        let primary = self.select();
+
+        let Some(secondary) = self.next_of(primary) else {
+            return self.query(primary, buf).await;
+        };
+
+        let delay = match hedge {
+            Hedge::Delay(delay) => delay,
+            Hedge::P95 => primary.samples.lock().unwrap().p95().unwrap_or(HEDGE_DELAY),
+        };
+
+        let mut query = self.race_query(primary, buf.clone());
+
+        let mut queries = match timeout(delay, &mut query).await {
+            Ok((_, Ok(res))) if is_answer(&res) => return Ok(res),
+            // primary failed in hedging delay. the next upstream is queried right away.
+            Ok(_) => Vec::new(),
+            Err(_) => vec![query],
+        };
+
+        secondary.metrics.hedges.fetch_add(1, Ordering::Relaxed);
+        queries.push(self.race_query(secondary, buf));
+        first_answer(queries).await
+    }
+
+    fn race_query<'a>(&'a self, upstream: &'a Arc<Upstream>, buf: Box<[u8]>) -> RaceFuture<'a> {
+        Box::pin(async move { (upstream, self.query(upstream, buf).await) })
+    }
+}
+
+type RaceFuture<'a> =
+    Pin<Box<dyn Future<Output = (&'a Arc<Upstream>, Result<Vec<u8>, Error>)> + Send + 'a>>;
+
+// poll queries until one of them returns NOERROR or NXDOMAIN response and cancel the rest of
+// them. the last response or error is returned when none of the responses is an answer.
+async fn first_answer(mut queries: Vec<RaceFuture<'_>>) -> Result<Vec<u8>, Error> {
+    let mut last = None;
+
+    poll_fn(|cx| {
+        let mut i = 0;
+        while i < queries.len() {
+            let Poll::Ready((upstream, res)) = queries[i].as_mut().poll(cx) else {
+                i += 1;
+                continue;
+            };
+            drop(queries.swap_remove(i));
+            match res {
+                Ok(res) if is_answer(&res) => {
+                    upstream.metrics.wins.fetch_add(1, Ordering::Relaxed);
+                    return Poll::Ready(Ok(res));
+                }
+                res => last = Some(res),
+            }
+        }
+        match queries.is_empty() {
+            true => Poll::Ready(last.take().unwrap()),
+            false => Poll::Pending,
+        }
+    })
+    .await
+}
+
+impl Proxy for GroupProxy {
+    async fn proxy(&self, buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
+        match (self.strategy, self.hedge) {
+            (Strategy::Race, _) => self.race(buf).await,
+            (_, Some(hedge)) => self.hedge(buf, hedge).await,
+            (_, None) => self.fallback(buf).await,
+        }
+    }
+
+    async fn close(mut self) {
+        self.shutdown.cancel();
+        self.tasks.close();
+        self.tasks.wait().await;
+
+        for upstream in mem::take(&mut self.upstreams).into_vec() {
+            if let Ok(upstream) = Arc::try_unwrap(upstream) {
+                upstream.proxy.close_dyn().await;
+            }
+        }
+    }
+}
+
+impl Drop for GroupProxy {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+// response with the answer or nonexistence of the name.
+fn is_answer(res: &[u8]) -> bool {
+    let code = rescode(res);
+    res.len() >= 12 && (code == ResultCode::NOERROR as u8 || code == ResultCode::NXDOMAIN as u8)
+}
+
+// result code is the low 4 bits of the fourth byte. RFC 1035 4.1.1
+fn rescode(res: &[u8]) -> u8 {
+    res.get(3)
+        .map(|b| b & 0x0F)
+        .unwrap_or(ResultCode::SERVFAIL as u8)
+}
+
+// log cumulative metrics of upstreams periodically. nothing is logged when no query is proxied
+// since last report.
+async fn report_metrics(upstreams: Box<[Arc<Upstream>]>) {
+    let mut interval = interval(METRICS_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.tick().await;
+
+    let mut last = 0;
+
+    loop {
+        interval.tick().await;
+
+        let queries = upstreams
+            .iter()
+            .map(|u| u.metrics.queries.load(Ordering::Relaxed))
+            .sum();
+        if queries == last {
+            continue;
+        }
+        last = queries;
+
+        for upstream in upstreams.iter() {
+            let metrics = &upstream.metrics;
+            info!(
+                "upstream {} metrics {{ queries: {}, failures: {}, wins: {}, hedges: {}, fallbacks: {}, latency: {:?}, down: {} }}",
+                upstream.name,
+                metrics.queries.load(Ordering::Relaxed),
+                metrics.failures.load(Ordering::Relaxed),
+                metrics.wins.load(Ordering::Relaxed),
+                metrics.hedges.load(Ordering::Relaxed),
+                metrics.fallbacks.load(Ordering::Relaxed),
+                Duration::from_micros(upstream.latency.load(Ordering::Relaxed)),
+                upstream.is_down()
+            );
+        }
+    }
+}
+
+// send synthetic query to upstream marked down until it's answered. regular queries can mark
+// upstream up in the mean time when all upstreams are down.
+async fn health_probe(upstream: Arc<Upstream>) {
+    while upstream.is_down() {
+        sleep(PROBE_INTERVAL).await;
+
+        let id = fastrand::u16(..);
+        let query = dns::probe_query(id);
+
+        match timeout(PROBE_TIMEOUT, upstream.proxy.proxy_dyn(query.into())).await {
+            Ok(Ok(mut res)) => {
+                let mut header = Header::new();
+                let healthy = header.read(&mut Buf::new(&mut res)).is_ok()
+                    && header.response
+                    && header.id == id
+                    && header.rescode != ResultCode::SERVFAIL;
+                if healthy {
+                    upstream.recover();
+                    return;
+                }
+                debug!("upstream {} health probe bad response", upstream.name);
+            }
+            Ok(Err(e)) => debug!("upstream {} health probe error: {e}", upstream.name),
+            Err(_) => debug!("upstream {} health probe timed out", upstream.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::future::pending;
+
+    use super::*;
+
+    const DEADLINE: Duration = Duration::from_millis(300);
+
+    // upstream never answering.
+    struct Hang;
+
+    impl Proxy for Hang {
+        async fn proxy(&self, _: Box<[u8]>) -> Result<Vec<u8>, Error> {
+            pending().await
+        }
+    }
+
+    // upstream answering every query with it's own message after delay.
+    struct Delay(Duration);
+
+    impl Proxy for Delay {
+        async fn proxy(&self, buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
+            sleep(self.0).await;
+            Ok(buf.into_vec())
+        }
+    }
+
+    fn upstream(proxy: impl Proxy + 'static, timeout: Option<Duration>, retries: u32) -> Upstream {
+        Upstream::new(String::new(), Box::new(proxy), 1, timeout, retries)
+    }
+
+    fn new_group(upstreams: Vec<Upstream>, strategy: Strategy, fallback: Fallback) -> GroupProxy {
+        GroupProxy::new(upstreams, strategy, None, fallback, DEADLINE)
+    }
+
+    #[tokio::test]
+    async fn attempt_timeout() {
+        let upstreams = || {
+            vec![
+                upstream(Hang, None, 0),
+                upstream(Hang, None, 2),
+                upstream(Hang, Some(Duration::from_millis(50)), 2),
+            ]
+        };
+
+        // deadline is shared with fallback query to the next upstream.
+        let group = new_group(upstreams(), Strategy::RoundRobin, Fallback::default());
+        let timeouts = group
+            .upstreams
+            .iter()
+            .map(|u| group.attempt_timeout(u).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(timeouts, [150, 50, 50]);
+
+        // upstreams are not queried one after another.
+        for (strategy, fallback) in [
+            (Strategy::Race, Fallback::default()),
+            (Strategy::RoundRobin, "none".parse().unwrap()),
+        ] {
+            let group = new_group(upstreams(), strategy, fallback);
+            let timeouts = group
+                .upstreams
+                .iter()
+                .map(|u| group.attempt_timeout(u).as_millis())
+                .collect::<Vec<_>>();
+            assert_eq!(timeouts, [300, 100, 50]);
+        }
+    }
+
+    #[tokio::test]
+    async fn hanging_primary() {
+        let group = new_group(
+            vec![
+                upstream(Hang, None, 0),
+                upstream(Delay(Duration::from_millis(10)), None, 0),
+            ],
+            Strategy::RoundRobin,
+            Fallback::default(),
+        );
+
+        // every other query goes to hanging upstream first and times out in time for fallback.
+        for _ in 0..4 {
+            let query = group.proxy(dns::probe_query(0).into());
+            assert!(timeout(DEADLINE, query).await.unwrap().is_ok());
+        }
+
+        group.close().await;
+    }
+
+    #[tokio::test]
+    async fn cancelled_query() {
+        let group = new_group(
+            vec![upstream(Delay(Duration::from_millis(50)), None, 0)],
+            Strategy::RoundRobin,
+            Fallback::default(),
+        );
+
+        // deadline of client cancels queries before upstream answers.
+        for _ in 0..FAIL_THRESHOLD * 2 {
+            let query = group.proxy(dns::probe_query(0).into());
+            assert!(timeout(Duration::from_millis(10), query).await.is_err());
+        }
+
+        let upstream = &group.upstreams[0];
+        assert_eq!(upstream.metrics.failures.load(Ordering::Relaxed), 0);
+        assert_eq!(upstream.failures.load(Ordering::Relaxed), 0);
+        assert_eq!(upstream.latency.load(Ordering::Relaxed), 0);
+        assert!(!upstream.is_down());
+
+        group.close().await;
+    }
+}
@@ -13,7 +13,7 @@ use crate::error::Error;
 
 use super::{udp::udp_resolve, Proxy};
 
-static DNS_MSG_HDR: HeaderValue = HeaderValue::from_static("application/dns-message");
+pub(crate) static DNS_MSG_HDR: HeaderValue = HeaderValue::from_static("application/dns-message");
 
 pub struct HttpProxy {
     cli: xitca_client::Client,
@@ -21,6 +21,11 @@ pub struct HttpProxy {
 }
 
 impl HttpProxy {
+    // validate uri of upstream without network io.
+    pub fn check_uri(uri: &str) -> Result<(), Error> {
+        Uri::try_from(uri).map(|_| ()).map_err(Into::into)
+    }
+
     pub async fn try_from_uri(uri: String, boot_strap_addr: SocketAddr) -> Result<Self, Error> {
         let uri = Uri::try_from(uri)?;
 
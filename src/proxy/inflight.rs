@@ -0,0 +1,304 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::{dns, error::Error};
+
+// random ids tried before searching for a free one.
+const ID_PROBES: usize = 8;
+
+// queries sent to upstream and waiting for response. query is sent with a random transaction id
+// and response is matched by the id and question so out of order or spoofed response can't be
+// handed to wrong waiter. RFC 5452 9.1
+pub(super) struct Inflight {
+    waiters: Mutex<HashMap<u16, Waiter>>,
+}
+
+struct Waiter {
+    // transaction id of the query from client.
+    id: u16,
+    question: Box<[u8]>,
+    // query is written to connection and it's response is lost with the connection.
+    sent: bool,
+    tx: oneshot::Sender<Vec<u8>>,
+}
+
+impl Inflight {
+    pub(super) fn new() -> Self {
+        Self {
+            waiters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // rewrite transaction id of query to a random one not in use and register waiter of it's
+    // response. error is returned when all ids are in use.
+    pub(super) fn register(&self, buf: &mut [u8]) -> Result<Ticket<'_>, Error> {
+        let question = dns::question(buf)
+            .ok_or("query without question can't be matched with response")?
+            .into();
+
+        let mut waiters = self.waiters.lock().unwrap();
+        if waiters.len() > u16::MAX as usize {
+            return Err(Error::from("transaction ids of upstream are exhausted"));
+        }
+
+        // random probing slows down when most of ids are in use. free id is searched from a
+        // random start after a few misses.
+        let id = (0..ID_PROBES)
+            .map(|_| fastrand::u16(..))
+            .find(|id| !waiters.contains_key(id))
+            .unwrap_or_else(|| {
+                let start = fastrand::u16(..);
+                (0..=u16::MAX)
+                    .map(|i| start.wrapping_add(i))
+                    .find(|id| !waiters.contains_key(id))
+                    .unwrap()
+            });
+
+        let (tx, rx) = oneshot::channel();
+        let waiter = Waiter {
+            id: u16::from_be_bytes([buf[0], buf[1]]),
+            question,
+            sent: false,
+            tx,
+        };
+        waiters.insert(id, waiter);
+        buf[..2].copy_from_slice(&id.to_be_bytes());
+
+        Ok(Ticket {
+            inflight: self,
+            id,
+            rx,
+        })
+    }
+
+    // mark waiter of registered query as sent. false is returned when the query is cancelled and
+    // should not be sent.
+    pub(super) fn sent(&self, buf: &[u8]) -> bool {
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+        let mut waiters = self.waiters.lock().unwrap();
+        match waiters.get_mut(&id) {
+            // id of cancelled query can be reused by another one waiting to be sent.
+            Some(waiter) if dns::question(buf).is_some_and(|q| *q == *waiter.question) => {
+                waiter.sent = true;
+                !waiter.tx.is_closed()
+            }
+            _ => false,
+        }
+    }
+
+    // hand response to it's waiter with id of client restored. response matching no waiter is
+    // returned back.
+    pub(super) fn complete(&self, mut buf: Vec<u8>) -> Result<(), Vec<u8>> {
+        if buf.len() < 12 {
+            return Err(buf);
+        }
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+
+        let waiter = {
+            let mut waiters = self.waiters.lock().unwrap();
+            // name of question is case insensitive and it's case can be altered by upstream.
+            let matched = waiters.get(&id).is_some_and(|waiter| {
+                dns::question(&buf).is_some_and(|q| q.eq_ignore_ascii_case(&waiter.question))
+            });
+            if !matched {
+                return Err(buf);
+            }
+            waiters.remove(&id).unwrap()
+        };
+
+        buf[..2].copy_from_slice(&waiter.id.to_be_bytes());
+        let _ = waiter.tx.send(buf);
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    pub(super) fn len(&self) -> usize {
+        self.waiters.lock().unwrap().len()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.waiters.lock().unwrap().is_empty()
+    }
+
+    // drop every waiter so they observe error. used when socket to upstream fails.
+    pub(super) fn clear(&self) {
+        self.waiters.lock().unwrap().clear();
+    }
+
+    // drop waiters of sent queries so they observe error. used when connection to upstream is
+    // lost. queries not sent yet are kept for the next connection.
+    pub(super) fn clear_sent(&self) {
+        self.waiters
+            .lock()
+            .unwrap()
+            .retain(|_, waiter| !waiter.sent);
+    }
+}
+
+// registered query waiting for response. waiter is removed when ticket is dropped so id of
+// cancelled or timed out query can be reused.
+pub(super) struct Ticket<'a> {
+    inflight: &'a Inflight,
+    id: u16,
+    rx: oneshot::Receiver<Vec<u8>>,
+}
+
+impl Ticket<'_> {
+    pub(super) async fn response(&mut self) -> Result<Vec<u8>, Error> {
+        (&mut self.rx)
+            .await
+            .map_err(|_| Error::from("connection to upstream is closed"))
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        // closed receiver tells waiter of this ticket apart from the one reusing the id after
+        // response is received.
+        self.rx.close();
+        let mut waiters = self.inflight.waiters.lock().unwrap();
+        if waiters.get(&self.id).is_some_and(|w| w.tx.is_closed()) {
+            waiters.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // query of name with transaction id.
+    fn query(id: u16, name: &str) -> Box<[u8]> {
+        let mut buf = id.to_be_bytes().to_vec();
+        buf.extend_from_slice(&[1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+        buf.extend_from_slice(&[0, 0, 1, 0, 1]);
+        buf.into()
+    }
+
+    // response from upstream echoing query with it's rewritten id.
+    fn response(query: &[u8]) -> Vec<u8> {
+        let mut buf = query.to_vec();
+        buf[2] |= 0x80;
+        buf
+    }
+
+    #[tokio::test]
+    async fn out_of_order() {
+        let inflight = Inflight::new();
+
+        let mut q1 = query(1, "a.example");
+        let mut q2 = query(2, "b.example");
+        let mut t1 = inflight.register(&mut q1).unwrap();
+        let mut t2 = inflight.register(&mut q2).unwrap();
+        assert_ne!(q1[..2], q2[..2]);
+
+        inflight.complete(response(&q2)).unwrap();
+        inflight.complete(response(&q1)).unwrap();
+
+        let r2 = t2.response().await.unwrap();
+        let r1 = t1.response().await.unwrap();
+        assert_eq!(r1[..2], [0, 1]);
+        assert_eq!(r1[12..], q1[12..]);
+        assert_eq!(r2[..2], [0, 2]);
+        assert_eq!(r2[12..], q2[12..]);
+
+        assert!(inflight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unmatched_question() {
+        let inflight = Inflight::new();
+
+        let mut q = query(1, "a.example");
+        let mut ticket = inflight.register(&mut q).unwrap();
+
+        // spoofed response with the right id and wrong question is rejected.
+        let mut spoofed = response(&query(0, "b.example"));
+        spoofed[..2].copy_from_slice(&q[..2]);
+        assert!(inflight.complete(spoofed).is_err());
+
+        // case of name can be altered by upstream.
+        let mut res = response(&q);
+        res[13] = b'A';
+        inflight.complete(res).unwrap();
+        assert_eq!(ticket.response().await.unwrap()[..2], [0, 1]);
+    }
+
+    // register queries until the given id is handed out. the rest of tickets are kept in
+    // tickets so their ids stay in use.
+    fn register_id<'a>(
+        inflight: &'a Inflight,
+        id: [u8; 2],
+        name: &str,
+        tickets: &mut Vec<Ticket<'a>>,
+    ) -> (Box<[u8]>, Ticket<'a>) {
+        loop {
+            let mut q = query(0, name);
+            let ticket = inflight.register(&mut q).unwrap();
+            if q[..2] == id {
+                return (q, ticket);
+            }
+            tickets.push(ticket);
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_then_reuse_id() {
+        let inflight = Inflight::new();
+        let mut tickets = Vec::new();
+
+        let mut q1 = query(1, "a.example");
+        let t1 = inflight.register(&mut q1).unwrap();
+        let id = [q1[0], q1[1]];
+
+        // late response of cancelled query is dropped and cancelled query is not sent.
+        drop(t1);
+        assert!(inflight.is_empty());
+        assert!(inflight.complete(response(&q1)).is_err());
+        assert!(!inflight.sent(&q1));
+
+        let (q2, mut t2) = register_id(&inflight, id, "b.example", &mut tickets);
+
+        // stale query with the reused id does not mark the new one as sent.
+        assert!(!inflight.sent(&q1));
+        inflight.clear_sent();
+        assert!(inflight.sent(&q2));
+        assert!(inflight.complete(response(&q1)).is_err());
+
+        inflight.complete(response(&q2)).unwrap();
+        let res = t2.response().await.unwrap();
+        assert_eq!(res[..2], [0, 0]);
+        assert_eq!(res[12..], q2[12..]);
+
+        // dropping answered ticket does not remove waiter reusing it's id.
+        let (q3, mut t3) = register_id(&inflight, id, "c.example", &mut tickets);
+        drop(t2);
+        inflight.complete(response(&q3)).unwrap();
+        assert_eq!(t3.response().await.unwrap()[12..], q3[12..]);
+    }
+
+    #[tokio::test]
+    async fn clear_sent() {
+        let inflight = Inflight::new();
+
+        let mut q1 = query(1, "a.example");
+        let mut q2 = query(2, "b.example");
+        let mut t1 = inflight.register(&mut q1).unwrap();
+        let mut t2 = inflight.register(&mut q2).unwrap();
+
+        // connection is lost after q1 is written.
+        assert!(inflight.sent(&q1));
+        inflight.clear_sent();
+        assert!(t1.response().await.is_err());
+
+        // q2 is sent on the next connection.
+        assert!(inflight.sent(&q2));
+        inflight.complete(response(&q2)).unwrap();
+        assert_eq!(t2.response().await.unwrap()[..2], [0, 2]);
+    }
+}
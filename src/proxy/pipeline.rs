@@ -0,0 +1,271 @@
+use core::{
+    fmt,
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+    time::Duration,
+};
+
+use std::{io, sync::Arc};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{debug, error, trace};
+
+use crate::error::Error;
+
+use super::inflight::Inflight;
+
+type Msg = Box<[u8]>;
+
+// duration of no query after which connection not kept warm is closed. RFC 7766 6.2.1
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// stream connection to upstream made by background task of pipeline.
+pub(super) trait Connect: fmt::Display + Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + 'static;
+
+    fn connect(&self) -> impl Future<Output = Result<Self::Stream, Error>> + Send;
+}
+
+// queries are pipelined on a persistent stream connection with 2 bytes length prefix and
+// responses are matched by transaction id and question as they can be out of order. connection
+// is driven by it's own background task. RFC 7766 6.2.1.1
+pub(super) struct Pipeline {
+    tx: mpsc::Sender<Msg>,
+    inflight: Arc<Inflight>,
+    // connection is established and queries sent to it are not waiting for connect.
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    live: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Pipeline {
+    // warm connection is made right away and made again after it's closed. otherwise connection
+    // is made when there is query to send and closed after it's idle.
+    pub(super) fn spawn<C>(connect: Arc<C>, warm: bool) -> Self
+    where
+        C: Connect,
+    {
+        let (tx, rx) = mpsc::channel(256);
+        let inflight = Arc::new(Inflight::new());
+        let live = Arc::new(AtomicBool::new(false));
+        let handle = tokio::spawn(run(connect, rx, inflight.clone(), live.clone(), warm));
+        Self {
+            tx,
+            inflight,
+            live,
+            handle,
+        }
+    }
+
+    pub(super) async fn query(&self, mut buf: Msg) -> Result<Vec<u8>, Error> {
+        let mut ticket = self.inflight.register(&mut buf)?;
+        self.tx.send(buf).await?;
+        ticket.response().await
+    }
+
+    // queries waiting for response.
+    #[cfg(feature = "tls")]
+    pub(super) fn load(&self) -> usize {
+        self.inflight.len()
+    }
+
+    #[cfg(feature = "tls")]
+    pub(super) fn is_live(&self) -> bool {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    // dropping sender makes background task finish in-flight queries and exit.
+    pub(super) async fn close(self) {
+        drop(self.tx);
+        let _ = self.handle.await;
+    }
+}
+
+async fn run<C>(
+    connect: Arc<C>,
+    mut rx: mpsc::Receiver<Msg>,
+    inflight: Arc<Inflight>,
+    live: Arc<AtomicBool>,
+    warm: bool,
+) where
+    C: Connect,
+{
+    let idle = (!warm).then_some(IDLE_TIMEOUT);
+    // query received while there is no connection. it's sent first after connection is made.
+    let mut msg = None;
+    loop {
+        if !warm && msg.is_none() {
+            match rx.recv().await {
+                Some(m) => msg = Some(m),
+                None => return,
+            }
+        }
+
+        match connect.connect().await {
+            Ok(stream) => {
+                live.store(true, Ordering::Relaxed);
+                let res = pipeline(stream, msg.take(), &mut rx, &inflight, idle).await;
+                live.store(false, Ordering::Relaxed);
+                // responses of queries sent on lost connection would never arrive. queries still
+                // in channel are sent on the next connection.
+                inflight.clear_sent();
+                match res {
+                    Ok(Exit::Closed) => return,
+                    Ok(Exit::Idle) => trace!("{connect} idle connection closed"),
+                    Err(e) => trace!("{connect} unexpected disconnect: {e}"),
+                }
+            }
+            Err(e) => {
+                error!("{connect} connect error: {e}");
+                time::sleep(Duration::from_secs(1)).await;
+                // proxy is dropped while connection can't be made.
+                if msg.is_none() && rx.is_closed() && rx.is_empty() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// reason of pipeline finishing without error.
+enum Exit {
+    // proxy is dropped and in-flight queries are answered.
+    Closed,
+    // no query is sent or waiting for response in idle timeout.
+    Idle,
+}
+
+async fn pipeline<S>(
+    stream: S,
+    msg: Option<Msg>,
+    rx: &mut mpsc::Receiver<Msg>,
+    inflight: &Arc<Inflight>,
+    idle: Option<Duration>,
+) -> io::Result<Exit>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, write) = tokio::io::split(stream);
+    let closing = Arc::new(AtomicBool::new(false));
+    let mut reader = tokio::spawn(read_loop(read, inflight.clone(), closing.clone()));
+    let res = write_loop(write, msg, rx, &mut reader, inflight, &closing, idle).await;
+    reader.abort();
+    res
+}
+
+// write queries until connection is closed by upstream, it's idle or proxy is dropped. queries
+// waiting in channel are batched into one write.
+async fn write_loop<S>(
+    mut write: WriteHalf<S>,
+    mut msg: Option<Msg>,
+    rx: &mut mpsc::Receiver<Msg>,
+    reader: &mut JoinHandle<io::Result<()>>,
+    inflight: &Inflight,
+    closing: &AtomicBool,
+    idle: Option<Duration>,
+) -> io::Result<Exit>
+where
+    S: AsyncWrite,
+{
+    let mut idle = idle.map(|dur| (dur, Box::pin(time::sleep(dur))));
+    let mut buf = Vec::new();
+    loop {
+        let next = match msg.take() {
+            Some(msg) => msg,
+            None => {
+                let next = poll_fn(|cx| {
+                    if let Poll::Ready(res) = Pin::new(&mut *reader).poll(cx) {
+                        return Poll::Ready(Err(match res {
+                            Ok(Err(e)) => e,
+                            _ => io::ErrorKind::UnexpectedEof.into(),
+                        }));
+                    }
+                    if let Poll::Ready(msg) = rx.poll_recv(cx) {
+                        return Poll::Ready(Ok(msg.ok_or(Exit::Closed)));
+                    }
+                    if let Some((dur, sleep)) = idle.as_mut() {
+                        // query waiting for response keeps connection open.
+                        while sleep.as_mut().poll(cx).is_ready() {
+                            if inflight.is_empty() {
+                                return Poll::Ready(Ok(Err(Exit::Idle)));
+                            }
+                            sleep.as_mut().reset(Instant::now() + *dur);
+                        }
+                    }
+                    Poll::Pending
+                });
+                match next.await? {
+                    Ok(msg) => msg,
+                    Err(Exit::Closed) => break,
+                    Err(Exit::Idle) => {
+                        let _ = write.shutdown().await;
+                        return Ok(Exit::Idle);
+                    }
+                }
+            }
+        };
+
+        if let Some((dur, sleep)) = idle.as_mut() {
+            sleep.as_mut().reset(Instant::now() + *dur);
+        }
+
+        buf.clear();
+        encode(&mut buf, &next, inflight);
+        while let Ok(msg) = rx.try_recv() {
+            encode(&mut buf, &msg, inflight);
+        }
+        if buf.is_empty() {
+            continue;
+        }
+        write.write_all(&buf).await?;
+        write.flush().await?;
+    }
+
+    // proxy is dropped. wait for responses of in-flight queries before closing connection.
+    closing.store(true, Ordering::SeqCst);
+    if !inflight.is_empty() {
+        let _ = reader.await;
+    }
+
+    Ok(Exit::Closed)
+}
+
+// cancelled query is skipped.
+fn encode(buf: &mut Vec<u8>, msg: &[u8], inflight: &Inflight) {
+    if !inflight.sent(msg) {
+        return;
+    }
+    buf.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    buf.extend_from_slice(msg);
+}
+
+// read responses until connection is closed or every in-flight query is answered after proxy
+// is dropped.
+async fn read_loop<S>(
+    mut read: ReadHalf<S>,
+    inflight: Arc<Inflight>,
+    closing: Arc<AtomicBool>,
+) -> io::Result<()>
+where
+    S: AsyncRead,
+{
+    let mut len = [0; 2];
+    loop {
+        read.read_exact(&mut len).await?;
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        read.read_exact(&mut buf).await?;
+        if inflight.complete(buf).is_err() {
+            debug!("dropping unmatched response from upstream");
+        }
+        if closing.load(Ordering::SeqCst) && inflight.is_empty() {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use core::net::SocketAddr;
+
+use std::{io, sync::Arc};
+
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint};
+use rustls::RootCertStore;
+use tokio::sync::Mutex;
+
+use crate::{app::try_iter, error::Error, proxy::udp::udp_resolve};
+
+use super::{parse_uri, Proxy};
+
+// max size of a dns message with two bytes length prefix.
+pub(crate) const MAX_LEN: usize = u16::MAX as usize + 2;
+
+pub struct QuicProxy {
+    endpoint: Endpoint,
+    addrs: Vec<SocketAddr>,
+    server_name: String,
+    conn: Mutex<Option<Connection>>,
+}
+
+impl QuicProxy {
+    // validate uri of upstream without network io.
+    pub fn check_uri(uri: &str) -> Result<(), Error> {
+        parse_uri(uri).map(|_| ())
+    }
+
+    pub async fn try_from_uri(uri: String, boot_strap_addr: SocketAddr) -> Result<Self, Error> {
+        let uri = parse_uri(&uri)?;
+
+        let host = uri.host().unwrap();
+
+        let port = uri.port_u16().unwrap_or(853);
+
+        let addrs = udp_resolve(boot_strap_addr, host, port).await?;
+
+        let mut root_certs = RootCertStore::empty();
+
+        root_certs.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        Self::with_root_certs(addrs, host.to_owned(), root_certs)
+    }
+
+    // construct proxy with resolved addresses of upstream and certificates it's trusted by.
+    pub(crate) fn with_root_certs(
+        addrs: Vec<SocketAddr>,
+        server_name: String,
+        root_certs: RootCertStore,
+    ) -> Result<Self, Error> {
+        let mut cfg = rustls::ClientConfig::builder()
+            .with_root_certificates(root_certs)
+            .with_no_client_auth();
+
+        // RFC 9250 4.1.1
+        cfg.alpn_protocols = vec![b"doq".to_vec()];
+
+        let cfg = ClientConfig::new(Arc::new(QuicClientConfig::try_from(cfg)?));
+
+        let bind = match addrs.first() {
+            Some(SocketAddr::V6(_)) => SocketAddr::from(([0u16; 8], 0)),
+            _ => SocketAddr::from(([0u8; 4], 0)),
+        };
+
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(cfg);
+
+        Ok(Self {
+            endpoint,
+            addrs,
+            server_name,
+            conn: Mutex::new(None),
+        })
+    }
+
+    // get live connection to upstream. reconnect when previous one is closed.
+    async fn connection(&self) -> Result<Connection, Error> {
+        let mut conn = self.conn.lock().await;
+
+        if let Some(conn) = conn.as_ref() {
+            if conn.close_reason().is_none() {
+                return Ok(conn.clone());
+            }
+        }
+
+        let c = try_iter(self.addrs.iter(), |addr| async move {
+            let conn = self.endpoint.connect(*addr, &self.server_name)?.await?;
+            Ok::<_, Error>(conn)
+        })
+        .await?;
+
+        *conn = Some(c.clone());
+
+        Ok(c)
+    }
+}
+
+impl Proxy for QuicProxy {
+    async fn proxy(&self, mut buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
+        if buf.len() < 2 {
+            return Err(Error::from(io::Error::from(io::ErrorKind::InvalidInput)));
+        }
+
+        // dns message id must be 0 for DoQ. RFC 9250 4.2.1
+        let id = [buf[0], buf[1]];
+        buf[..2].fill(0);
+
+        let conn = self.connection().await?;
+
+        // connection can be closed between liveness check and opening stream. retry once with
+        // new connection.
+        let (mut send, mut recv) = match conn.open_bi().await {
+            Ok(stream) => stream,
+            Err(_) => self.connection().await?.open_bi().await?,
+        };
+
+        send.write_all(&(buf.len() as u16).to_be_bytes()).await?;
+        send.write_all(&buf).await?;
+        send.finish()?;
+
+        let mut res = recv.read_to_end(MAX_LEN).await?;
+
+        let len = match res.get(..2) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => 0,
+        };
+
+        res.drain(..res.len().min(2));
+        res.truncate(len);
+
+        if res.len() < 2 {
+            return Err(Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+
+        res[..2].copy_from_slice(&id);
+
+        Ok(res)
+    }
+
+    async fn close(self) {
+        self.endpoint.close(0u32.into(), b"");
+        self.endpoint.wait_idle().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quinn::{crypto::rustls::QuicServerConfig, ServerConfig};
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    use core::time::Duration;
+
+    use crate::dns;
+
+    use super::*;
+
+    // quic server with self signed certificate answering every query with it's own message
+    // and closing connection after each answer.
+    fn server() -> (Endpoint, RootCertStore) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+
+        let mut root_certs = RootCertStore::empty();
+        root_certs.add(cert.cert.der().clone()).unwrap();
+
+        let mut cfg = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key)
+            .unwrap();
+        cfg.alpn_protocols = vec![b"doq".to_vec()];
+        let cfg = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(cfg).unwrap()));
+
+        let endpoint = Endpoint::server(cfg, SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        (endpoint, root_certs)
+    }
+
+    #[tokio::test]
+    async fn query() {
+        let (server, root_certs) = server();
+        let addr = server.local_addr().unwrap();
+
+        let handle = tokio::spawn(async move {
+            let mut queries = Vec::new();
+            for _ in 0..2 {
+                let conn = server.accept().await.unwrap().await.unwrap();
+                let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+                let req = recv.read_to_end(MAX_LEN).await.unwrap();
+                send.write_all(&req).await.unwrap();
+                send.finish().unwrap();
+                let _ = send.stopped().await;
+                // idle connection closed by server.
+                conn.close(0u32.into(), b"");
+                queries.push(req);
+            }
+            queries
+        });
+
+        let proxy = QuicProxy::with_root_certs(vec![addr], "localhost".into(), root_certs).unwrap();
+
+        let query = dns::probe_query(0x1234);
+        for _ in 0..2 {
+            let res = proxy.proxy(query.clone().into()).await.unwrap();
+            // id of query is restored.
+            assert_eq!(res, query);
+            // wait for close of idle connection reaching proxy.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        for req in handle.await.unwrap() {
+            let (len, msg) = req.split_at(2);
+            assert_eq!(u16::from_be_bytes([len[0], len[1]]) as usize, msg.len());
+            // RFC 9250 4.2.1
+            assert_eq!(msg[..2], [0, 0]);
+            assert_eq!(msg[2..], query[2..]);
+        }
+
+        proxy.close().await;
+    }
+
+    #[tokio::test]
+    async fn no_address() {
+        // bootstrap server answering with response without any record.
+        let boot_strap = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let boot_strap_addr = boot_strap.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, addr) = boot_strap.recv_from(&mut buf).await.unwrap();
+            let res = dns::error_response(&mut buf[..len], dns::ResultCode::NOERROR);
+            boot_strap.send_to(&res, addr).await.unwrap();
+        });
+
+        let res = QuicProxy::try_from_uri("quic://localhost".into(), boot_strap_addr).await;
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use crate::{
+    config::Route,
+    dns::{Buf, Packet},
+    error::Error,
+};
+
+use super::{group::GroupProxy, Proxy};
+
+// proxy forwarding query to upstream group by the longest suffix of question name matched by
+// routes. query not matched by any route goes to the first group as default.
+pub struct RouteProxy {
+    groups: Box<[GroupProxy]>,
+    trie: Trie,
+}
+
+impl RouteProxy {
+    // groups must not be empty. route to group not found in groups is ignored.
+    pub fn new(groups: Vec<(String, GroupProxy)>, routes: Vec<Route>) -> Self {
+        assert!(!groups.is_empty(), "default group is missing");
+        let mut trie = Trie::new();
+        for route in routes {
+            if let Some(idx) = groups.iter().position(|(name, _)| *name == route.group) {
+                trie.insert(&route.suffix, idx);
+            }
+        }
+        Self {
+            groups: groups.into_iter().map(|(_, group)| group).collect(),
+            trie,
+        }
+    }
+
+    fn route(&self, buf: &mut [u8]) -> &GroupProxy {
+        let mut packet = Packet::new_ref();
+        let idx = match packet.read(&mut Buf::new(buf)) {
+            Ok(_) => packet
+                .questions
+                .first()
+                .and_then(|question| self.trie.get(&question.name)),
+            Err(_) => None,
+        };
+        &self.groups[idx.unwrap_or(0)]
+    }
+}
+
+impl Proxy for RouteProxy {
+    async fn proxy(&self, mut buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
+        self.route(&mut buf).proxy(buf).await
+    }
+
+    async fn close(self) {
+        for group in self.groups.into_vec() {
+            group.close().await;
+        }
+    }
+}
+
+// label trie keyed from the rightmost label of domain name. value of a node is the group of
+// route ending at it.
+struct Trie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<Box<str>, usize>,
+    value: Option<usize>,
+}
+
+impl Trie {
+    fn new() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+        }
+    }
+
+    // suffix is expected to be lower case without leading and trailing dot. empty suffix is the
+    // root. route inserted later replaces the former one with the same suffix.
+    fn insert(&mut self, suffix: &str, value: usize) {
+        let mut idx = 0;
+        for label in labels(suffix) {
+            idx = match self.nodes[idx].children.get(label) {
+                Some(&next) => next,
+                None => {
+                    let next = self.nodes.len();
+                    self.nodes.push(Node::default());
+                    self.nodes[idx].children.insert(label.into(), next);
+                    next
+                }
+            };
+        }
+        self.nodes[idx].value = Some(value);
+    }
+
+    // value of the longest suffix matching name.
+    fn get(&self, name: &str) -> Option<usize> {
+        let mut idx = 0;
+        let mut value = self.nodes[idx].value;
+        for label in labels(name) {
+            match self.nodes[idx].children.get(label) {
+                Some(&next) => idx = next,
+                None => break,
+            }
+            value = self.nodes[idx].value.or(value);
+        }
+        value
+    }
+}
+
+// labels of domain name from the rightmost one.
+fn labels(name: &str) -> impl Iterator<Item = &str> {
+    name.rsplit('.').filter(|label| !label.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trie_longest_match() {
+        let mut trie = Trie::new();
+        trie.insert("com", 1);
+        trie.insert("example.com", 2);
+        trie.insert("a.b.example.com", 3);
+
+        assert_eq!(trie.get("com"), Some(1));
+        assert_eq!(trie.get("other.com"), Some(1));
+        assert_eq!(trie.get("example.com"), Some(2));
+        assert_eq!(trie.get("www.example.com"), Some(2));
+        // partial path without value falls back to the closest route above it.
+        assert_eq!(trie.get("b.example.com"), Some(2));
+        assert_eq!(trie.get("x.a.b.example.com"), Some(3));
+        assert_eq!(trie.get("example.org"), None);
+        // labels are matched whole.
+        assert_eq!(trie.get("notexample.com"), Some(1));
+    }
+
+    #[test]
+    fn trie_root() {
+        let mut trie = Trie::new();
+        assert_eq!(trie.get("example.com"), None);
+
+        trie.insert("", 1);
+        trie.insert("example.com", 2);
+
+        assert_eq!(trie.get(""), Some(1));
+        assert_eq!(trie.get("example.org"), Some(1));
+        assert_eq!(trie.get("www.example.com"), Some(2));
+    }
+
+    #[test]
+    fn trie_replace() {
+        let mut trie = Trie::new();
+        trie.insert("example.com", 1);
+        trie.insert("example.com", 2);
+        assert_eq!(trie.get("example.com"), Some(2));
+    }
+
+    #[test]
+    fn trie_fully_qualified_name() {
+        let mut trie = Trie::new();
+        trie.insert("lan", 1);
+        // trailing dot of fully qualified name is ignored.
+        assert_eq!(trie.get("host.lan."), Some(1));
+        assert_eq!(trie.get("lan."), Some(1));
+    }
+}
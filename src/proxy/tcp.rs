@@ -0,0 +1,46 @@
+use core::net::SocketAddr;
+
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+
+use crate::error::Error;
+
+use super::{
+    pipeline::{Connect, Pipeline},
+    Proxy,
+};
+
+// queries are pipelined on a persistent tcp connection. connection is made when there is query
+// to send and made again after it's closed. RFC 7766 6.2.1.1
+pub struct TcpProxy {
+    conn: Pipeline,
+}
+
+impl TcpProxy {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            conn: Pipeline::spawn(Arc::new(addr), false),
+        }
+    }
+}
+
+impl Connect for SocketAddr {
+    type Stream = TcpStream;
+
+    async fn connect(&self) -> Result<Self::Stream, Error> {
+        let stream = TcpStream::connect(*self).await?;
+        let _ = stream.set_nodelay(true);
+        Ok(stream)
+    }
+}
+
+impl Proxy for TcpProxy {
+    async fn proxy(&self, buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
+        self.conn.query(buf).await
+    }
+
+    async fn close(self) {
+        self.conn.close().await;
+    }
+}
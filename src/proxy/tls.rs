@@ -1,50 +1,43 @@
-use core::{convert::Infallible, fmt, net::SocketAddr, time::Duration};
+use core::{fmt, net::SocketAddr};
 
-use std::{collections::VecDeque, error, io, sync::Arc};
+use std::sync::Arc;
 
 use http::Uri;
-use tokio::{
-    sync::{mpsc, oneshot},
-    time,
-};
-use tracing::{error, trace};
-use xitca_io::{
-    bytes::{Buf, BufInterest, BufRead, BufWrite, WriteBuf},
-    io::{AsyncIo, Interest},
-    net::TcpStream,
-};
+use xitca_io::{io::PollIoAdapter, net::TcpStream};
 use xitca_tls::rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
-use xitca_unsafe_collection::futures::{Select, SelectOutput};
-
-use crate::{error::Error, proxy::udp::udp_resolve};
 
-use super::Proxy;
+use crate::{config::TlsPool, error::Error, proxy::udp::udp_resolve};
 
-type PagedBytesMut = xitca_io::bytes::PagedBytesMut<4096>;
-
-type Msg = (Box<[u8]>, oneshot::Sender<Vec<u8>>);
+use super::{
+    parse_uri,
+    pipeline::{Connect, Pipeline},
+    Proxy,
+};
 
 type TlsStream = xitca_tls::rustls::TlsStream<ClientConnection, TcpStream>;
 
+// in-flight queries of live connection before query is sent to connection not made yet.
+const BUSY: usize = 32;
+
+// pool of pipelined connections each driven by it's own background task. RFC 7766 7
 pub struct TlsProxy {
-    tx: mpsc::Sender<Msg>,
+    conns: Box<[Pipeline]>,
 }
 
 impl TlsProxy {
-    pub async fn try_from_uri(uri: String, boot_strap_addr: SocketAddr) -> Result<Self, Error> {
-        let uri = Uri::try_from(uri)?;
+    pub async fn try_from_uri(
+        uri: String,
+        boot_strap_addr: SocketAddr,
+        pool: TlsPool,
+    ) -> Result<Self, Error> {
+        let (uri, server_name) = Self::check_uri(&uri)?;
 
-        let host = match uri.host() {
-            Some(host) => host,
-            None => return Err(Error::from(InvalidUri(uri))),
-        };
+        let host = uri.host().unwrap();
 
         let port = uri.port_u16().unwrap_or(853);
 
         let addrs = udp_resolve(boot_strap_addr, host, port).await?;
 
-        let server_name = host.to_owned().try_into()?;
-
         let mut root_certs = RootCertStore::empty();
 
         root_certs.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
@@ -53,182 +46,82 @@ impl TlsProxy {
             .with_root_certificates(root_certs)
             .with_no_client_auth();
 
-        let cfg = Arc::new(cfg);
-
-        let (tx, rx) = mpsc::channel(256);
-
-        let mut ctx = TlsContext::new(rx);
-
-        tokio::spawn(async move {
-            let host = uri.host().unwrap();
-            loop {
-                match connect(&addrs, &cfg, &server_name).await {
-                    Ok(stream) => {
-                        let Err(e) = ctx.pipeline_io(stream).await else {
-                            return;
-                        };
-                        trace!("{host} unexpected disconnect: {e}");
-                        if !ctx.wait_for_reconnect().await {
-                            return;
-                        }
-                    }
-                    Err(e) => {
-                        error!("{host} connect error: {e}");
-                        ctx.reset();
-                        time::sleep(Duration::from_secs(1)).await;
-                    }
-                };
-            }
+        let target = Arc::new(Target {
+            uri,
+            server_name,
+            addrs,
+            cfg: Arc::new(cfg),
         });
 
-        Ok(Self { tx })
-    }
-}
-
-struct TlsContext {
-    len: Option<usize>,
-    buf_read: PagedBytesMut,
-    buf_write: WriteBuf,
-    queue: VecDeque<oneshot::Sender<Vec<u8>>>,
-    rx: mpsc::Receiver<Msg>,
-}
-
-impl TlsContext {
-    fn new(rx: mpsc::Receiver<Msg>) -> Self {
-        Self {
-            len: None,
-            buf_read: PagedBytesMut::new(),
-            buf_write: WriteBuf::new(),
-            queue: VecDeque::new(),
-            rx,
-        }
-    }
+        let conns = (0..pool.size)
+            .map(|i| Pipeline::spawn(target.clone(), i < pool.warm))
+            .collect();
 
-    fn reset(&mut self) {
-        self.len = None;
-        self.buf_read.split();
-        self.buf_write.clear();
-        self.queue.clear();
+        Ok(Self { conns })
     }
 
-    fn try_read(&mut self, stream: &mut TlsStream) -> io::Result<()> {
-        self.buf_read.do_io(stream).map(|_| self.decode())
-    }
-
-    fn try_write(&mut self, stream: &mut TlsStream) -> io::Result<()> {
-        self.buf_write.do_io(stream).map_err(|e| {
-            self.buf_write.clear();
-            e
-        })
+    // validate uri and server name of upstream without network io.
+    pub fn check_uri(uri: &str) -> Result<(Uri, ServerName<'static>), Error> {
+        let uri = parse_uri(uri)?;
+        let server_name = uri.host().unwrap().to_owned().try_into()?;
+        Ok((uri, server_name))
     }
+}
 
-    fn decode(&mut self) {
-        loop {
-            match self.len {
-                Some(l) if self.buf_read.chunk().len() >= l => {
-                    let buf = self.buf_read.split_to(l).to_vec();
-                    let _ = self.queue.pop_front().unwrap().send(buf);
-                    self.len = None;
-                }
-                None if self.buf_read.chunk().len() > 2 => {
-                    let l = u16::from_be_bytes(self.buf_read.chunk()[..2].try_into().unwrap());
-                    self.len = Some(l as usize);
-                    self.buf_read.advance(2);
-                }
-                _ => return,
-            }
+impl TlsProxy {
+    // query goes to the least loaded live connection. the rest of connections are only used
+    // when every live one is busy so they are not made for light load.
+    fn select(&self) -> &Pipeline {
+        let live = self
+            .conns
+            .iter()
+            .filter(|conn| conn.is_live())
+            .min_by_key(|conn| conn.load());
+        match live {
+            Some(conn) if conn.load() < BUSY => conn,
+            _ => self
+                .conns
+                .iter()
+                .min_by_key(|conn| (conn.load(), !conn.is_live()))
+                .unwrap(),
         }
     }
+}
 
-    fn encode(&mut self, (buf, tx): (Box<[u8]>, oneshot::Sender<Vec<u8>>)) {
-        let len = (buf.len() as u16).to_be_bytes();
-        let _ = self.buf_write.write_buf(|b| {
-            b.extend_from_slice(&len);
-            b.extend_from_slice(&buf);
-            Ok::<_, Infallible>(())
-        });
-        self.queue.push_back(tx);
-    }
-
-    async fn pipeline_io(&mut self, mut stream: TlsStream) -> io::Result<()> {
-        loop {
-            let interest = if self.buf_write.want_write_io() {
-                Interest::READABLE | Interest::WRITABLE
-            } else {
-                Interest::READABLE
-            };
-
-            match self.rx.recv().select(stream.ready(interest)).await {
-                // got new pipelined request. write to buffer and move on.
-                SelectOutput::A(Some(msg)) => self.encode(msg),
-                // tls stream is ready to be read/write.
-                SelectOutput::B(res) => {
-                    let ready = res?;
-                    if ready.is_readable() {
-                        self.try_read(&mut stream)?;
-                    }
-                    if ready.is_writable() && self.try_write(&mut stream).is_err() {
-                        break;
-                    }
-                }
-                // proxy is dropped from app.
-                SelectOutput::A(None) => break,
-            }
-        }
-
-        loop {
-            let want_read = !self.queue.is_empty();
-            let want_write = self.buf_write.want_write_io();
-            let interest = match (want_read, want_write) {
-                (true, true) => Interest::READABLE | Interest::WRITABLE,
-                (true, false) => Interest::READABLE,
-                (false, true) => Interest::WRITABLE,
-                (false, false) => break,
-            };
-            let ready = stream.ready(interest).await?;
-            if ready.is_readable() {
-                self.try_read(&mut stream)?;
-            }
-            if ready.is_writable() {
-                let _ = self.try_write(&mut stream);
-            }
-        }
-
-        Ok(())
-    }
+// upstream every connection of pool connects to.
+struct Target {
+    uri: Uri,
+    server_name: ServerName<'static>,
+    addrs: Vec<SocketAddr>,
+    cfg: Arc<ClientConfig>,
+}
 
-    async fn wait_for_reconnect(&mut self) -> bool {
-        self.reset();
-        self.rx.recv().await.map(|msg| self.encode(msg)).is_some()
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.uri.host().unwrap())
     }
 }
 
-async fn connect(
-    addrs: &[SocketAddr],
-    cfg: &Arc<ClientConfig>,
-    server_name: &ServerName<'static>,
-) -> Result<TlsStream, Error> {
-    let stream = crate::app::try_iter(addrs.iter(), TcpStream::connect).await?;
-    let _ = stream.set_nodelay(true);
-    let conn = ClientConnection::new(cfg.clone(), server_name.clone())?;
-    TlsStream::handshake(stream, conn).await.map_err(Into::into)
+impl Connect for Target {
+    type Stream = PollIoAdapter<TlsStream>;
+
+    async fn connect(&self) -> Result<Self::Stream, Error> {
+        let stream = crate::app::try_iter(self.addrs.iter(), TcpStream::connect).await?;
+        let _ = stream.set_nodelay(true);
+        let conn = ClientConnection::new(self.cfg.clone(), self.server_name.clone())?;
+        let stream = TlsStream::handshake(stream, conn).await?;
+        Ok(PollIoAdapter(stream))
+    }
 }
 
 impl Proxy for TlsProxy {
     async fn proxy(&self, buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
-        let (tx, rx) = oneshot::channel();
-        self.tx.send((buf, tx)).await?;
-        rx.await.map_err(Into::into)
+        self.select().query(buf).await
     }
-}
 
-#[derive(Debug)]
-struct InvalidUri(Uri);
-
-impl fmt::Display for InvalidUri {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} is not valid uri.", self.0)
+    async fn close(self) {
+        for conn in self.conns.into_vec() {
+            conn.close().await;
+        }
     }
 }
-
-impl error::Error for InvalidUri {}
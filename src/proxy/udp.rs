@@ -1,55 +1,124 @@
 use core::net::SocketAddr;
 
-use tokio::{net::UdpSocket, sync::Semaphore};
+use std::sync::Arc;
 
-use crate::error::Error;
+use tokio::{net::UdpSocket, task::JoinHandle};
+use tracing::debug;
 
-use super::Proxy;
+use crate::{dns, error::Error};
 
-// TODO: the efficiency of UdpProxy is a big no.
-// To improve one must find a way to reuse a single UdpSocket for all dns look up. probably
-// coupled with a dns cache to isolate the impact of out of order datagram received from upstream.
+use super::{inflight::Inflight, tcp::TcpProxy, Proxy};
+
+// connected sockets shared by queries to the same upstream.
+const SOCKETS: usize = 4;
+
+// queries are spread across a pool of long lived sockets with random transaction id. responses
+// are matched back to queries by id and question. truncated response is retried over tcp.
 pub struct UdpProxy {
     addr: SocketAddr,
-    permit: Semaphore,
+    sockets: Box<[Socket]>,
+    _readers: Readers,
+    tcp: TcpProxy,
+}
+
+// connected socket and queries waiting for response from it.
+struct Socket {
+    socket: Arc<UdpSocket>,
+    inflight: Arc<Inflight>,
+}
+
+// tasks receiving responses from sockets. aborted when proxy is dropped.
+struct Readers(Box<[JoinHandle<()>]>);
+
+impl Drop for Readers {
+    fn drop(&mut self) {
+        for reader in self.0.iter() {
+            reader.abort();
+        }
+    }
 }
 
 impl UdpProxy {
     pub async fn try_from_addr(addr: SocketAddr) -> Result<Self, Error> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0u8; 4], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+
+        let mut sockets = Vec::with_capacity(SOCKETS);
+        let mut readers = Vec::with_capacity(SOCKETS);
+
+        for _ in 0..SOCKETS {
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(addr).await?;
+            let socket = Arc::new(socket);
+            let inflight = Arc::new(Inflight::new());
+            readers.push(tokio::spawn(read(socket.clone(), inflight.clone())));
+            sockets.push(Socket { socket, inflight });
+        }
+
         Ok(Self {
             addr,
-            permit: Semaphore::new(64),
+            sockets: sockets.into(),
+            _readers: Readers(readers.into()),
+            tcp: TcpProxy::new(addr),
         })
     }
 }
 
 impl Proxy for UdpProxy {
-    async fn proxy(&self, buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
-        let _permit = self.permit.acquire().await?;
-
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket.connect(self.addr).await?;
+    async fn proxy(&self, mut buf: Box<[u8]>) -> Result<Vec<u8>, Error> {
+        let Socket { socket, inflight } = &self.sockets[fastrand::usize(..self.sockets.len())];
 
+        let mut ticket = inflight.register(&mut buf)?;
         socket.send(&buf).await?;
 
-        let mut buf = vec![0; 512];
+        let res = ticket.response().await?;
+        if !dns::is_truncated(&res) {
+            return Ok(res);
+        }
 
-        let n = socket.recv(&mut buf).await?;
+        debug!(
+            "truncated response from upstream {}. retrying over tcp",
+            self.addr
+        );
+        // restore id of query from response.
+        buf[..2].copy_from_slice(&res[..2]);
+        self.tcp.proxy(buf).await
+    }
 
-        buf.truncate(n);
+    async fn close(self) {
+        self.tcp.close().await;
+    }
+}
 
-        Ok(buf)
+// receive responses from upstream and hand them to waiting queries.
+async fn read(socket: Arc<UdpSocket>, inflight: Arc<Inflight>) {
+    // upstream can send response as large as the payload size advertised by query.
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        match socket.recv(&mut buf).await {
+            Ok(n) => {
+                if inflight.complete(buf[..n].to_vec()).is_err() {
+                    debug!("dropping unmatched response from upstream");
+                }
+            }
+            // icmp error of connected socket is reported by recv. queries sent on the socket
+            // are failed instead of waiting for timeout.
+            Err(e) => {
+                debug!("upstream socket error: {e}");
+                inflight.clear();
+            }
+        }
     }
 }
 
-#[cfg(any(feature = "tls", feature = "https"))]
+#[cfg(any(feature = "tls", feature = "https", feature = "quic"))]
 pub(super) async fn udp_resolve(
     boot_strap_addr: SocketAddr,
     hostname: &str,
     port: u16,
 ) -> std::io::Result<Vec<SocketAddr>> {
-    use tracing::debug;
-
     use core::time::Duration;
 
     use tokio::time::timeout;
@@ -107,7 +176,16 @@ pub(super) async fn udp_resolve(
                 }
             }
         })
-        .collect();
+        .collect::<Vec<_>>();
+
+    // host without A record has no address to connect to. error is returned instead of empty
+    // list so upstream is rejected on construction.
+    if res.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no address is resolved for upstream host: {hostname}"),
+        ));
+    }
 
     Ok(res)
 }
@@ -1,3 +1,13 @@
 use core::{future::Future, pin::Pin};
 
+use tracing::Level;
+use tracing_subscriber::{fmt::Formatter, reload::Handle, EnvFilter};
+
 pub type BoxFuture<'f, O> = Pin<Box<dyn Future<Output = O> + Send + 'f>>;
+
+// handle for changing log level at runtime.
+pub type LogHandle = Handle<EnvFilter, Formatter>;
+
+pub fn log_filter(level: Level) -> EnvFilter {
+    EnvFilter::new(format!("dns_proxy={}", level.as_str()))
+}